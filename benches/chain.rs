@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use k256::ecdsa::SigningKey;
+
+use blockchain::blockchain::{
+    block::{Block, GenesisConfig},
+    global_state::GlobalState,
+    testutil::{fund, make_keypair, spend, TempDir},
+    transaction::{
+        Output,
+        Transaction,
+        TransactionBuilder,
+        UTXOSet,
+        COINBASE_MATURITY,
+        DEFAULT_CHAIN_ID
+    }
+};
+
+
+const UTXO_COUNTS: [usize; 2] = [1_000, 10_000];
+const CHAIN_LENGTH: u32 = 20;
+const KEY_COUNT: usize = 16;
+const FUNDING_AMOUNT: u32 = 1_000_000;
+const MINING_DIFFICULTY: u32 = 12;
+
+// Usage: cargo bench --features testutil
+fn bench_is_valid(c: &mut Criterion) {
+    let mut group = c.benchmark_group("is_valid");
+    for utxo_count in UTXO_COUNTS {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, outpoint) = fund(&mut utxo_set, &pubkey, FUNDING_AMOUNT);
+        for _ in 1..utxo_count {
+            fund(&mut utxo_set, &make_keypair().1, FUNDING_AMOUNT);
+        }
+        let tx = spend(&key, outpoint, &[(make_keypair().1, 10)]);
+
+        group.bench_with_input(BenchmarkId::from_parameter(utxo_count),
+            &utxo_set, |b, utxo_set| b.iter(||
+                tx.is_valid(utxo_set, 0, DEFAULT_CHAIN_ID).unwrap()));
+    }
+    group.finish();
+}
+
+fn bench_mine(c: &mut Criterion) {
+    let (_, pubkey) = make_keypair();
+    let mut block = Block::from_mempool(&Default::default(), &UTXOSet::new(),
+        &pubkey, 10, 0, DEFAULT_CHAIN_ID);
+
+    let mut group = c.benchmark_group("mine");
+    group.sample_size(20);
+    group.bench_function(BenchmarkId::from_parameter(MINING_DIFFICULTY),
+        |b| b.iter(|| {
+            // A new timestamp per round so every search starts afresh.
+            block.header.time_stamp += Duration::from_secs(1);
+            block.mine(MINING_DIFFICULTY);
+        }));
+    group.finish();
+}
+
+fn bench_replay(c: &mut Criterion) {
+    let data_dir = TempDir::new();
+    let keys = (0..KEY_COUNT).map(|_| make_keypair().0).collect::<Vec<_>>();
+    let (_, miner) = make_keypair();
+
+    let genesis = keys
+        .iter()
+        .fold(GenesisConfig::new().set_difficulty(1), |genesis, key|
+            genesis.add_output(Output::new()
+                .set_pubkey(*key.verifying_key())
+                .set_amount(FUNDING_AMOUNT)
+                .collect()));
+    let state = GlobalState::open(data_dir.path(), genesis, false, false)
+        .unwrap();
+
+    for _ in 0..COINBASE_MATURITY {
+        state.mine_next_block(&miner).unwrap();
+    }
+    for _ in 0..CHAIN_LENGTH {
+        let utxo_set = state.utxo_snapshot();
+        let height = **state.block_height.lock().unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            let recipient = &keys[(i + 1) % keys.len()];
+            state.submit_transaction(transfer(key, recipient, &utxo_set,
+                    height))
+                .unwrap();
+        }
+        state.mine_next_block(&miner).unwrap();
+    }
+
+    let mut group = c.benchmark_group("replay");
+    group.sample_size(10);
+    group.bench_function(
+        BenchmarkId::from_parameter(COINBASE_MATURITY + CHAIN_LENGTH),
+        |b| b.iter(|| state.verify_chain(false).unwrap()));
+    group.finish();
+}
+
+fn transfer(from: &SigningKey, to: &SigningKey, utxo_set: &UTXOSet,
+        height: u32) -> Transaction {
+
+    TransactionBuilder::new()
+        .add_recipient(*to.verifying_key(), 10)
+        .set_fee(1)
+        .build(from, utxo_set, height)
+        .unwrap()
+}
+
+criterion_group!(benches, bench_is_valid, bench_mine, bench_replay);
+criterion_main!(benches);
@@ -0,0 +1,87 @@
+use k256::{
+    ecdsa::VerifyingKey,
+    sha2::{Digest, Sha256}
+};
+use ripemd::Ripemd160;
+
+
+pub const ADDRESS_VERSION: u8 = 0x00;
+
+pub type PubkeyHash = [u8; 20];
+
+#[derive(Debug)]
+pub enum AddressError {
+    InvalidEncoding,
+    InvalidLength(usize),
+    InvalidChecksum,
+    UnknownVersion(u8)
+}
+
+pub fn pubkey_hash(key: &VerifyingKey) -> PubkeyHash {
+    let sha = Sha256::digest(key.to_encoded_point(true).as_bytes());
+    Ripemd160::digest(sha).into()
+}
+
+pub fn pubkey_to_address(key: &VerifyingKey) -> String {
+    let mut payload = vec![ADDRESS_VERSION];
+    payload.extend_from_slice(&pubkey_hash(key));
+    payload.extend_from_slice(&checksum(&payload));
+
+    bs58::encode(payload).into_string()
+}
+
+pub fn address_to_pubkey_hash(addr: &str)
+        -> Result<PubkeyHash, AddressError> {
+
+    let decoded = bs58::decode(addr.trim())
+        .into_vec()
+        .map_err(|_| AddressError::InvalidEncoding)?;
+    if decoded.len() != 25 {
+        return Err(AddressError::InvalidLength(decoded.len()));
+    }
+
+    let (payload, expected_checksum) = decoded.split_at(21);
+    if checksum(payload) != expected_checksum {
+        return Err(AddressError::InvalidChecksum);
+    }
+
+    if payload[0] != ADDRESS_VERSION {
+        return Err(AddressError::UnknownVersion(payload[0]));
+    }
+
+    Ok(payload[1..].try_into().unwrap())
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = Sha256::digest(Sha256::digest(payload));
+    hash[..4].try_into().unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testutil::make_keypair;
+
+    #[test]
+    fn address_round_trips_to_the_pubkey_hash() {
+        let (_, pubkey) = make_keypair();
+        let address = pubkey_to_address(&pubkey);
+
+        assert!(address.starts_with('1'));
+        assert_eq!(address_to_pubkey_hash(&address).unwrap(),
+            pubkey_hash(&pubkey));
+    }
+
+    #[test]
+    fn flipped_character_fails_the_checksum() {
+        let (_, pubkey) = make_keypair();
+        let mut address = pubkey_to_address(&pubkey).into_bytes();
+        let middle = address.len() / 2;
+        address[middle] = if address[middle] == b'2' { b'3' } else { b'2' };
+        let address = String::from_utf8(address).unwrap();
+
+        assert!(matches!(address_to_pubkey_hash(&address),
+            Err(AddressError::InvalidChecksum)));
+    }
+}
@@ -1,14 +1,29 @@
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     fs::File,
-    time::SystemTime, io::{BufReader, Read}
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write}
 };
 
 use bincode;
-use k256::{sha2::{Digest, Sha256}, pkcs8::der::Writer};
+use k256::{
+    ecdsa::VerifyingKey,
+    sha2::{Digest, Sha256}
+};
+use log::info;
 use serde::{Deserialize, Serialize};
 
+use super::mempool::{ancestor_packages, dependency_order, Mempool};
 use super::transaction::{
+    short_hash,
+    short_tx_id,
+    DEFAULT_CHAIN_ID,
+    Output,
+    UTXODelta,
+    UTXOEntry,
     Sha256Hash,
     Transaction,
     TransactionValidityError,
@@ -18,35 +33,144 @@ use super::transaction::{
 use rand_core::OsRng;
 
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Block {
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const HALVING_INTERVAL: u32 = 210_000;
+pub const RETARGET_INTERVAL: u32 = 144;
+pub const TARGET_BLOCK_TIME: Duration = Duration::from_secs(60);
+const MAX_FUTURE_BLOCK_TIME: Duration = Duration::from_secs(2 * 60 * 60);
+const GENESIS_TIME_STAMP: Duration = Duration::from_secs(1_700_000_000);
+const GENESIS_DIFFICULTY: u32 = 20;
+const CANCEL_CHECK_INTERVAL: u64 = 4096;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockHeader {
     pub previous_block: Sha256Hash,
     pub time_stamp: SystemTime,
-    tx_list: Vec<Transaction>,
+    pub merkle_root: Sha256Hash,
+    pub difficulty: u32,
     nonce: u64
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Block {
+    pub header: BlockHeader,
+    tx_list: Vec<Transaction>
+}
+
 #[derive(Debug)]
 pub enum BlockValidityError {
     InvalidHash,
+    InvalidMerkleRoot,
     InvalidTransaction,
-    InvalidMinerReward
+    InvalidMinerReward,
+    RewardOverflow,
+    MultipleCoinbase,
+    MissingCoinbase,
+    MisplacedCoinbase,
+    InvalidCoinbaseInputs,
+    DoubleSpend,
+    WrongDifficulty,
+    UnknownParent,
+    WrongParent,
+    WrongGenesis,
+    TimestampTooOld,
+    TimestampTooFarInFuture,
+    BlockTooLarge,
+    PrunedHistory,
+    CheckpointMismatch,
+    ReorgTooDeep,
+    InvalidCoinbaseHeight,
+    Unreadable
 }
 
-impl Block {
+#[derive(Debug)]
+pub enum BlockReadError {
+    Io(io::Error),
+    Truncated(u64),
+    Corrupt(u64)
+}
+
+pub struct BlockIter {
+    reader: BufReader<File>,
+    offset: u64,
+    len: u64,
+    done: bool
+}
+
+pub struct BlockRevIter {
+    reader: BufReader<File>,
+    offset: u64,
+    done: bool
+}
+
+pub struct GenesisConfig {
+    time_stamp: SystemTime,
+    outputs: Vec<Output>,
+    difficulty: u32,
+    chain_id: u32,
+    checkpoints: Vec<(u32, Sha256Hash)>,
+    assume_valid: Option<Sha256Hash>
+}
+
+impl BlockHeader {
     pub fn new() -> Self {
-        Block {
+        BlockHeader {
             previous_block: [0; 32],
             time_stamp: SystemTime::now(),
-            tx_list: Vec::new(),
+            merkle_root: merkle_root(&[]),
+            difficulty: 0,
             nonce: 0
         }
     }
 
+    pub fn hash(&self) -> Sha256Hash {
+        let serialized_header = bincode::serialize(self)
+            .expect("Unable to serialize block header");
+
+        let mut hasher = Sha256::new();
+        hasher.update(&serialized_header);
+        hasher
+            .finalize()
+            .try_into()
+            .expect("Wrong len")
+    }
+
+    fn mining_template(&self) -> (Vec<u8>, usize) {
+        let serialized_header = bincode::serialize(self)
+            .expect("Unable to serialize block header");
+        let nonce_index = serialized_header.len() - size_of::<u64>();
+
+        let mut probe = serialized_header.clone();
+        probe[nonce_index..].copy_from_slice(&(!self.nonce).to_le_bytes());
+        let probe: BlockHeader = bincode::deserialize(&probe)
+            .expect("Unable to deserialize block header");
+        assert_eq!(probe.nonce, !self.nonce,
+            "Nonce must be the last serialized field of the block header");
+
+        (serialized_header, nonce_index)
+    }
+
+    pub fn is_valid_pow(&self, difficulty: u32) -> bool {
+        let base = [0u8; 32];
+        are_first_n_bits_equal(&base, &self.hash(), difficulty as usize)
+    }
+}
+
+impl Block {
+    pub fn new() -> Self {
+        Block {
+            header: BlockHeader::new(),
+            tx_list: Vec::new()
+        }
+    }
+
     pub fn from_file(file: &mut BufReader<File>) -> Option<Self> {
         let mut size = [0u8; 4];
-        file.read_exact(&mut size).unwrap();
-        let size = u32::from_ne_bytes(size);
+        if file.read_exact(&mut size).is_err() {
+            return None;
+        }
+        let size = u32::from_le_bytes(size);
 
         let mut buffer = vec![0; size as usize];
         let mut buffer = buffer.get_mut(..).unwrap();
@@ -65,7 +189,7 @@ impl Block {
             return None;
         }
         file.read_exact(&mut size).unwrap();
-        let size = u32::from_ne_bytes(size);
+        let size = u32::from_le_bytes(size);
 
         let mut buffer = vec![0; size as usize];
         let mut buffer = buffer.get_mut(..).unwrap();
@@ -77,76 +201,375 @@ impl Block {
         Some(bincode::deserialize(&buffer).unwrap())
     }
 
-    pub fn from_mempool(mempool: &HashSet<Transaction>, utxo_set: &UTXOSet)
-            -> Self {
+    pub fn from_mempool(mempool: &HashSet<Transaction>, utxo_set: &UTXOSet,
+            miner_pubkey: &VerifyingKey, reward: u32, height: u32,
+            chain_id: u32) -> Self {
+
+        Block::from_mempool_with_limit(mempool, utxo_set, miner_pubkey, reward,
+            height, chain_id, MAX_BLOCK_SIZE)
+    }
+
+    pub fn from_mempool_with_limit(mempool: &HashSet<Transaction>,
+            utxo_set: &UTXOSet, miner_pubkey: &VerifyingKey, reward: u32,
+            height: u32, chain_id: u32, max_bytes: usize) -> Self {
 
         let mut block = Block::new();
-        let mut lowest_fee = u32::MAX;
-
-        for tx in mempool {
-            let fee = tx.is_valid(utxo_set).unwrap();
-            if block.tx_list.len() < 5 {
-                block.add(tx.clone());
-                if fee < lowest_fee {
-                    lowest_fee = fee;
-                }
+
+        let mut coinbase = Transaction::new();
+        coinbase.set_locktime(height);
+        coinbase.add_output(Output::new()
+            .set_pubkey(*miner_pubkey)
+            .set_amount(block_subsidy(height, reward, HALVING_INTERVAL))
+            .collect()
+        );
+        let mut block_size = bincode::serialized_size(&block).unwrap()
+            + bincode::serialized_size(&coinbase).unwrap();
+
+        let ordered = dependency_order(mempool.iter());
+        let fees = validate_in_sequence(&ordered, utxo_set, height, chain_id,
+            true);
+        let candidates = ordered
+            .into_iter()
+            .zip(fees)
+            .filter_map(|(tx, fee)| {
+                let fee = fee.ok()?;
+                let size = bincode::serialized_size(tx).unwrap();
+                Some((tx.calculate_id(), (tx, fee as u64, size)))
+            })
+            .collect::<HashMap<_, _>>();
+        let packages = ancestor_packages(&candidates
+            .values()
+            .map(|(tx, _, _)| *tx)
+            .collect::<Vec<_>>());
+
+        let mut selected = HashSet::new();
+        let mut skipped = HashSet::new();
+        loop {
+            let best = candidates
+                .iter()
+                .filter(|(tx_id, _)| !selected.contains(*tx_id))
+                .filter_map(|(tx_id, (tx, _, _))| {
+                    let package = packages
+                        .get(tx_id)?
+                        .difference(&selected)
+                        .copied()
+                        .collect::<Vec<_>>();
+                    if package.iter().any(|id| skipped.contains(id)) {
+                        return None;
+                    }
+
+                    let (fee, size) = package
+                        .iter()
+                        .map(|id| &candidates[id])
+                        .fold((0, 0), |(fee, size), (_, tx_fee, tx_size)|
+                            (fee + tx_fee, size + tx_size));
+                    Some((*tx_id, tx.time_stamp, package, fee, size))
+                })
+                .max_by(|(id_a, time_a, _, fee_a, size_a),
+                        (id_b, time_b, _, fee_b, size_b)| {
+                    (fee_a * size_b)
+                        .cmp(&(fee_b * size_a))
+                        .then(time_b.cmp(time_a))
+                        .then(id_b.cmp(id_a))
+                });
+
+            let (tx_id, _, package, _, size) = match best {
+                Some(best) => best,
+                None => break
+            };
+            if block_size + size > max_bytes as u64 {
+                skipped.insert(tx_id);
                 continue;
             }
 
-            if fee > lowest_fee {
-                lowest_fee = block.remove_lowest_fee_transaction(utxo_set)
-                    .unwrap();
-                block.add(tx.clone());
-                if fee < lowest_fee {
-                    lowest_fee = fee;
-                }
-            }
+            selected.extend(package);
+            block_size += size;
         }
 
+        let selected = selected.iter().map(|tx_id| candidates[tx_id].0);
+        for tx in dependency_order(selected) {
+            block.add(tx.clone());
+        }
+
+        block.add_coinbase(miner_pubkey, reward, utxo_set, height, chain_id);
+        block.update_merkle_root();
         block
     }
 
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
     pub fn set_previous_block(&mut self, previous: &Sha256Hash) {
-        self.previous_block.copy_from_slice(previous);
+        self.header.previous_block.copy_from_slice(previous);
     }
 
     pub fn add(&mut self, tx: Transaction) {
         self.tx_list.push(tx);
     }
 
-    pub fn hash(&self) -> Sha256Hash {
-        let serialized_block = bincode::serialize(self)
-            .expect("Unable to serialize block");
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.tx_list
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(&serialized_block);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("Wrong len")
+    pub fn from_parts(header: BlockHeader, tx_list: Vec<Transaction>)
+            -> Result<Self, BlockValidityError> {
+
+        let block = Block { header, tx_list };
+        let tx_ids = block.tx_list
+            .iter()
+            .map(|tx| tx.calculate_id())
+            .collect::<Vec<_>>();
+        if merkle_root(&tx_ids) != block.header.merkle_root {
+            return Err(BlockValidityError::InvalidMerkleRoot);
+        }
+
+        Ok(block)
+    }
+
+    pub fn short_tx_ids(&self) -> Vec<u64> {
+        self.tx_list
+            .iter()
+            .map(|tx| short_tx_id(&tx.calculate_id()))
+            .collect()
+    }
+
+    pub fn to_pruned(&self) -> Self {
+        Block {
+            header: self.header.clone(),
+            tx_list: Vec::new()
+        }
+    }
+
+    pub fn is_pruned(&self) -> bool {
+        self.tx_list.is_empty()
+    }
+
+    pub fn add_coinbase(&mut self, miner_pubkey: &VerifyingKey, reward: u32,
+            utxo_set: &UTXOSet, height: u32, chain_id: u32) {
+
+        if let Some(tx) = self.tx_list.first() {
+            if tx.inputs().is_empty() {
+                self.tx_list.remove(0);
+            }
+        }
+
+        let txs = self.tx_list.iter().collect::<Vec<_>>();
+        let fees = validate_in_sequence(&txs, utxo_set, height, chain_id, true)
+            .into_iter()
+            .filter_map(Result::ok)
+            .fold(0u32, |acc, fee| acc.saturating_add(fee));
+
+        let subsidy = block_subsidy(height, reward, HALVING_INTERVAL);
+        let mut coinbase = Transaction::new();
+        coinbase.set_locktime(height);
+        coinbase.add_output(Output::new()
+            .set_pubkey(*miner_pubkey)
+            .set_amount(subsidy.saturating_add(fees))
+            .collect()
+        );
+
+        self.tx_list.insert(0, coinbase);
+    }
+
+    pub fn is_valid_timestamp(&self, previous_timestamps: &[SystemTime],
+            now: SystemTime) -> Result<(), BlockValidityError> {
+
+        let start = previous_timestamps.len()
+            .saturating_sub(MEDIAN_TIME_SPAN);
+        let mut recent = previous_timestamps[start..].to_vec();
+        recent.sort();
+        if let Some(median) = recent.get(recent.len() / 2) {
+            if self.header.time_stamp <= *median {
+                return Err(BlockValidityError::TimestampTooOld);
+            }
+        }
+
+        if self.header.time_stamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockValidityError::TimestampTooFarInFuture);
+        }
+
+        Ok(())
+    }
+
+    pub fn update_merkle_root(&mut self) {
+        let tx_ids = self.tx_list
+            .iter()
+            .map(|tx| tx.calculate_id())
+            .collect::<Vec<_>>();
+
+        self.header.merkle_root = merkle_root(&tx_ids);
+    }
+
+    pub fn merkle_proof(&self, tx_index: usize) -> Vec<(Sha256Hash, bool)> {
+        let mut level = self.tx_list
+            .iter()
+            .map(|tx| tx.calculate_id())
+            .collect::<Vec<_>>();
+
+        let mut proof = Vec::new();
+        let mut index = tx_index;
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(*level.last().unwrap());
+            }
+
+            let sibling_on_left = index % 2 == 1;
+            proof.push((level[index ^ 1], sibling_on_left));
+
+            level = level
+                .chunks(2)
+                .map(|pair| merkle_parent(&pair[0], &pair[1]))
+                .collect();
+            index /= 2;
+        }
+
+        proof
+    }
+
+    pub fn hash(&self) -> Sha256Hash {
+        self.header.hash()
     }
 
     pub fn is_valid_block(&self, difficulty: u32, reward: u32,
-            utxo_set: &UTXOSet) -> Result<(), BlockValidityError>
+            utxo_set: &UTXOSet, height: u32, chain_id: u32)
+            -> Result<(), BlockValidityError>
     {
-        let base = [0u8; 32];
-        let hash = self.hash();
-        if !are_first_n_bits_equal(&base, &hash, difficulty as usize) {
+        self.is_valid_in_context(&self.header.previous_block, &[],
+            difficulty, reward, utxo_set, height, chain_id)
+    }
+
+    pub fn is_valid_genesis(&self) -> Result<(), BlockValidityError> {
+        if self.header.previous_block != [0u8; 32] {
+            return Err(BlockValidityError::WrongParent);
+        }
+
+        if !self.header.is_valid_pow(self.header.difficulty) {
             return Err(BlockValidityError::InvalidHash);
         }
 
-        let mut expected_miner_reward = reward;
-        let mut actual_miner_reward = 0;
-        for tx in &self.tx_list {
-            match tx.is_valid(&utxo_set) {
-                Ok(val) => expected_miner_reward += val,
+        let tx_ids = self.tx_list
+            .iter()
+            .map(|tx| tx.calculate_id())
+            .collect::<Vec<_>>();
+        if merkle_root(&tx_ids) != self.header.merkle_root {
+            return Err(BlockValidityError::InvalidMerkleRoot);
+        }
 
-                Err(err) => match err {
-                    TransactionValidityError::InvalidOutputAmount(val) =>
-                        actual_miner_reward += val,
+        if self.tx_list.len() != 1 || !self.tx_list[0].is_coinbase() {
+            return Err(BlockValidityError::InvalidTransaction);
+        }
 
-                    _ => return Err(BlockValidityError::InvalidTransaction)
-                }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn is_valid_in_context(&self, prev_hash: &Sha256Hash,
+            previous_timestamps: &[SystemTime], difficulty: u32, reward: u32,
+            utxo_set: &UTXOSet, height: u32, chain_id: u32)
+            -> Result<(), BlockValidityError> {
+
+        self.validate_in_context(prev_hash, previous_timestamps, difficulty,
+            reward, utxo_set, height, chain_id, true)
+    }
+
+    // Proof of work, structure and amounts are always checked; only the
+    // input signatures can be skipped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn validate_in_context(&self, prev_hash: &Sha256Hash,
+            previous_timestamps: &[SystemTime], difficulty: u32, reward: u32,
+            utxo_set: &UTXOSet, height: u32, chain_id: u32,
+            verify_signatures: bool) -> Result<(), BlockValidityError> {
+
+        if self.header.previous_block != *prev_hash {
+            return Err(BlockValidityError::WrongParent);
+        }
+
+        let size = bincode::serialized_size(self).unwrap();
+        if size > MAX_BLOCK_SIZE as u64 {
+            return Err(BlockValidityError::BlockTooLarge);
+        }
+
+        self.is_valid_timestamp(previous_timestamps, SystemTime::now())?;
+
+        if self.header.difficulty != difficulty {
+            return Err(BlockValidityError::WrongDifficulty);
+        }
+
+        if !self.header.is_valid_pow(difficulty) {
+            return Err(BlockValidityError::InvalidHash);
+        }
+
+        let tx_ids = self.tx_list
+            .iter()
+            .map(|tx| tx.calculate_id())
+            .collect::<Vec<_>>();
+        if merkle_root(&tx_ids) != self.header.merkle_root {
+            return Err(BlockValidityError::InvalidMerkleRoot);
+        }
+
+        let claims_reward_with_inputs = self.tx_list
+            .first()
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.validate(utxo_set, height, chain_id,
+                verify_signatures))
+            .is_some_and(|res| matches!(res,
+                Err(TransactionValidityError::InvalidOutputAmount(_))));
+        if claims_reward_with_inputs {
+            return Err(BlockValidityError::InvalidCoinbaseInputs);
+        }
+
+        match self.tx_list.iter().position(|tx| tx.is_coinbase()) {
+            None => return Err(BlockValidityError::MissingCoinbase),
+            Some(0) => {}
+            Some(_) => return Err(BlockValidityError::MisplacedCoinbase)
+        }
+
+        let coinbase_count = self.tx_list
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .count();
+        if coinbase_count > 1 {
+            return Err(BlockValidityError::MultipleCoinbase);
+        }
+
+        // Txids leave out the timestamp, so the height in the locktime is what
+        // keeps a coinbase from repeating an earlier one and overwriting its
+        // unspent outputs.
+        if self.tx_list[0].locktime() != height {
+            return Err(BlockValidityError::InvalidCoinbaseHeight);
+        }
+
+        let mut spent_outpoints = HashSet::<(Sha256Hash, u32)>::new();
+        for input in self.tx_list.iter().flat_map(|tx| tx.inputs()) {
+            let outpoint = (input.core.tx_id, input.core.output_id);
+            if !spent_outpoints.insert(outpoint) {
+                return Err(BlockValidityError::DoubleSpend);
+            }
+        }
+
+        let actual_miner_reward = match self.tx_list[0]
+                .is_valid(utxo_set, height, chain_id) {
+            Ok(_) => 0,
+            Err(TransactionValidityError::InvalidOutputAmount(val)) => val,
+            Err(_) => return Err(BlockValidityError::InvalidTransaction)
+        };
+
+        let mut expected_miner_reward = block_subsidy(height, reward,
+            HALVING_INTERVAL);
+        let txs = self.tx_list[1..].iter().collect::<Vec<_>>();
+        let results = validate_in_sequence(&txs, utxo_set, height, chain_id,
+            verify_signatures);
+        for res in results {
+            match res {
+                Ok(val) => expected_miner_reward = expected_miner_reward
+                    .checked_add(val)
+                    .ok_or(BlockValidityError::RewardOverflow)?,
+                Err(_) => return Err(BlockValidityError::InvalidTransaction)
             }
         }
 
@@ -158,55 +581,162 @@ impl Block {
     }
 
     pub fn mine(&mut self, difficulty: u32) {
-        let mut serialized_block = bincode::serialize(&self)
-            .expect("Unable to serialize block");
+        self.mine_cancellable(difficulty, &AtomicBool::new(false));
+    }
+
+    pub fn mine_cancellable(&mut self, difficulty: u32, cancel: &AtomicBool)
+            -> bool {
+
+        self.update_merkle_root();
+        self.header.difficulty = difficulty;
+        let (mut serialized_header, nonce_index_on_array) =
+            self.header.mining_template();
 
         let base = [0u8; 32];
 
         let mut nonce = 0u64;
-        let nonce_index_on_array = serialized_block.len() - 8 as usize;
+        serialized_header[nonce_index_on_array..]
+            .copy_from_slice(&nonce.to_le_bytes());
         loop {
-            let hash: Sha256Hash = Sha256::digest(&serialized_block)
+            if nonce.is_multiple_of(CANCEL_CHECK_INTERVAL)
+                    && cancel.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            let hash: Sha256Hash = Sha256::digest(&serialized_header)
                 .try_into()
                 .expect("Wrong len");
 
             if are_first_n_bits_equal(&base, &hash, difficulty as usize) {
-                self.nonce = nonce;
-                return;
+                self.header.nonce = nonce;
+                debug_assert_eq!(self.hash(), hash);
+                info!("[MINED BLOCK][{}]", short_hash(&hash));
+                return true;
             }
 
             nonce += 1;
-            serialized_block[nonce_index_on_array..]
+            serialized_header[nonce_index_on_array..]
                 .copy_from_slice(&nonce.to_le_bytes());
         }
     }
 
-    pub fn update_utxo_set(&self, utxo_set: &mut UTXOSet) {
-        for tx in &self.tx_list {
-            for input in &tx.inputs {
-                utxo_set.remove(&(input.core.tx_id, input.core.output_id));
+    pub fn mine_parallel(&mut self, difficulty: u32, threads: usize) {
+        self.update_merkle_root();
+        self.header.difficulty = difficulty;
+        let (serialized_header, nonce_index_on_array) =
+            self.header.mining_template();
+
+        let threads = threads.max(1);
+        let found = AtomicBool::new(false);
+        let found_nonce = AtomicU64::new(0);
+
+        thread::scope(|scope| {
+            for offset in 0..threads {
+                let mut serialized_header = serialized_header.clone();
+                let found = &found;
+                let found_nonce = &found_nonce;
+
+                scope.spawn(move || {
+                    let base = [0u8; 32];
+                    let mut nonce = offset as u64;
+                    while !found.load(Ordering::Relaxed) {
+                        serialized_header[nonce_index_on_array..]
+                            .copy_from_slice(&nonce.to_le_bytes());
+                        let hash: Sha256Hash =
+                            Sha256::digest(&serialized_header).into();
+
+                        if are_first_n_bits_equal(&base, &hash,
+                                difficulty as usize) {
+                            if found.compare_exchange(false, true,
+                                    Ordering::SeqCst, Ordering::SeqCst)
+                                    .is_ok() {
+                                found_nonce.store(nonce, Ordering::SeqCst);
+                            }
+                            return;
+                        }
+
+                        nonce += threads as u64;
+                    }
+                });
             }
-            for (i, output) in tx.outputs.iter().enumerate() {
-                utxo_set.insert((tx.calculate_id(), i as u32), output.clone());
+        });
+
+        self.header.nonce = found_nonce.load(Ordering::SeqCst);
+        debug_assert!(self.header.is_valid_pow(difficulty));
+        info!("[MINED BLOCK][{}]", short_hash(&self.hash()));
+    }
+
+    pub fn apply(&self, utxo_set: &mut UTXOSet, height: u32)
+            -> Vec<((Sha256Hash, u32), UTXOEntry)> {
+
+        self.tx_list
+            .iter()
+            .flat_map(|tx| apply_transaction(tx, utxo_set, height))
+            .collect()
+    }
+
+    pub fn utxo_deltas(&self, height: u32) -> Vec<UTXODelta> {
+        let mut deltas = Vec::new();
+        for tx in &self.tx_list {
+            let tx_id = tx.calculate_id();
+            let is_coinbase = tx.is_coinbase();
+
+            deltas.extend(tx.inputs().iter().map(|input|
+                UTXODelta::Remove((input.core.tx_id, input.core.output_id))
+            ));
+            deltas.extend(tx.outputs().iter().enumerate().map(|(i, output)|
+                UTXODelta::Insert((tx_id, i as u32), UTXOEntry {
+                    output: output.clone(),
+                    height,
+                    is_coinbase
+                })
+            ));
+        }
+
+        deltas
+    }
+
+    pub fn unapply(&self, utxo_set: &mut UTXOSet,
+            spent: Vec<((Sha256Hash, u32), UTXOEntry)>) {
+
+        let created = self.tx_list
+            .iter()
+            .map(|tx| (tx.calculate_id(), tx.outputs().len() as u32))
+            .collect::<HashMap<_, _>>();
+
+        for (tx_id, output_count) in &created {
+            for i in 0..*output_count {
+                utxo_set.remove(&(*tx_id, i));
             }
         }
+        utxo_set.extend(spent
+            .into_iter()
+            .filter(|((tx_id, _), _)| !created.contains_key(tx_id)));
     }
 
-    pub fn update_mempool(&self, mempool: &mut HashSet<Transaction>) {
+    pub fn update_mempool(&self, mempool: &mut Mempool) {
+        // Whatever spent the same outputs as a confirmed transaction can no
+        // longer confirm, and neither can anything built on top of it.
         for tx in &self.tx_list {
-            mempool.remove(&tx);
+            mempool.remove(tx);
+            for conflict in mempool.conflicts(tx) {
+                mempool.remove_with_descendants(&conflict);
+            }
         }
     }
 
     pub fn rewind(&self, utxo_set: &mut UTXOSet,
             utxos_to_add: &mut HashSet<(Sha256Hash, u32)>)  {
 
-        for tx in &self.tx_list {
-            for i in 0..tx.outputs.len() {
-                utxo_set.remove(&(tx.calculate_id(), i as u32));
-                utxos_to_add.remove(&(tx.calculate_id(), i as u32));
+        // Undone newest first so an output created and spent within this
+        // block is dropped again instead of being left pending.
+        for tx in self.tx_list.iter().rev() {
+            let tx_id = tx.calculate_id();
+            for i in 0..tx.outputs().len() {
+                utxo_set.remove(&(tx_id, i as u32));
+                utxos_to_add.remove(&(tx_id, i as u32));
             }
-            for input in &tx.inputs {
+            for input in tx.inputs() {
                 utxos_to_add.insert((input.core.tx_id, input.core.output_id));
             }
         }
@@ -216,81 +746,463 @@ impl Block {
         let serialized_block = bincode::serialize(self).unwrap();
         let len = serialized_block.len() as u32;
 
-        file.write(&len.to_ne_bytes()).unwrap();
-        file.write(&serialized_block).unwrap();
-        file.write(&len.to_ne_bytes()).unwrap();
+        file.write_all(&len.to_le_bytes()).unwrap();
+        file.write_all(&serialized_block).unwrap();
+        file.write_all(&len.to_le_bytes()).unwrap();
     }
 
     pub fn add_pending_utxos_to_utxo_set(&self,  utxo_set: &mut UTXOSet,
-            utxos_to_add: &mut HashSet<(Sha256Hash, u32)>) {
+            utxos_to_add: &mut HashSet<(Sha256Hash, u32)>, height: u32) {
 
         for tx in &self.tx_list {
-            for (i, output) in tx.outputs.iter().enumerate() {
+            for (i, output) in tx.outputs().iter().enumerate() {
                 if let Some(val) = utxos_to_add
                         .take(&(tx.calculate_id(), i as u32)) {
 
-                    utxo_set.insert(val, output.clone());
+                    utxo_set.insert(val, UTXOEntry {
+                        output: output.clone(),
+                        height,
+                        is_coinbase: tx.is_coinbase()
+                    });
                 }
             }
         }
     }
 
-    pub fn remove_lowest_fee_transaction(&mut self, utxo_set: &UTXOSet)
-            -> Option<u32> {
-
-        let mut lowest_fee_id: Option<u32> = None;
-        let mut lowest_fee: Option<u32> = None;
-        let mut second_lowest_fee: Option<u32> = None;
-        for (i, tx) in self.tx_list.iter().enumerate() {
-            let fee = tx.is_valid(utxo_set).unwrap();
+    pub fn remove_lowest_fee_transaction(&mut self, utxo_set: &UTXOSet,
+            height: u32, chain_id: u32) -> Option<u32> {
 
-            if let None = lowest_fee_id {
-                lowest_fee_id = Some(i as u32);
-                lowest_fee = Some(fee);
-                continue;
-            }
+        let (lowest_fee_id, _) = self.tx_list
+            .iter()
+            .enumerate()
+            .filter_map(|(i, tx)| {
+                Some((i, tx.is_valid(utxo_set, height, chain_id).ok()?))
+            })
+            .min_by_key(|(_, fee)| *fee)?;
 
-            if fee < lowest_fee.unwrap() {
-                lowest_fee_id = Some(i as u32);
-                second_lowest_fee = lowest_fee;
-                lowest_fee = Some(fee);
-            }
-        }
+        self.tx_list.remove(lowest_fee_id);
 
-        if let Some(i) = lowest_fee_id {
-            self.tx_list.remove(i as usize);
-        }
-
-        second_lowest_fee
+        self.tx_list
+            .iter()
+            .filter_map(|tx| tx.is_valid(utxo_set, height, chain_id).ok())
+            .min()
     }
 
     pub fn update_all_pending_utxos(chain: &mut BufReader<File>,
             utxo_set: &mut UTXOSet,
-            utxos_to_add: &mut HashSet<(Sha256Hash, u32)>) {
+            utxos_to_add: &mut HashSet<(Sha256Hash, u32)>, height: u32) {
 
         let mut bytes_rewinded = 0;
+        let mut height = height;
 
         while utxos_to_add.len() > 0 {
             let mut size = [0u8; 4];
             chain.seek_relative(-4).unwrap();
             chain.read_exact(&mut size).unwrap();
-            let size: u32 = bincode::deserialize(&size).unwrap();
+            let size = u32::from_le_bytes(size);
             bytes_rewinded += 8 + size;
 
             let block = Block::from_file_backwads(&mut *chain).unwrap();
-            block.add_pending_utxos_to_utxo_set(&mut *utxo_set, &mut *utxos_to_add);
+            block.add_pending_utxos_to_utxo_set(&mut *utxo_set,
+                &mut *utxos_to_add, height);
+            height = height.saturating_sub(1);
         }
 
         chain.seek_relative(bytes_rewinded as i64).unwrap();
     }
 }
 
+impl GenesisConfig {
+    pub fn new() -> Self {
+        GenesisConfig {
+            time_stamp: UNIX_EPOCH + GENESIS_TIME_STAMP,
+            outputs: Vec::new(),
+            difficulty: GENESIS_DIFFICULTY,
+            chain_id: DEFAULT_CHAIN_ID,
+            checkpoints: Vec::new(),
+            assume_valid: None
+        }
+    }
+
+    pub fn set_time_stamp(mut self, time_stamp: SystemTime) -> Self {
+        self.time_stamp = time_stamp;
+        self
+    }
+
+    pub fn add_output(mut self, output: Output) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    pub fn set_difficulty(mut self, difficulty: u32) -> Self {
+        self.difficulty = difficulty;
+        self
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+
+    pub fn set_chain_id(mut self, chain_id: u32) -> Self {
+        self.chain_id = chain_id;
+        self
+    }
+
+    pub fn chain_id(&self) -> u32 {
+        self.chain_id
+    }
+
+    pub fn add_checkpoint(mut self, height: u32, hash: Sha256Hash) -> Self {
+        self.checkpoints.push((height, hash));
+        self
+    }
+
+    pub fn checkpoints(&self) -> &[(u32, Sha256Hash)] {
+        &self.checkpoints
+    }
+
+    // The assumed-valid block has to be one of the checkpoints, which is
+    // what pins it to a height.
+    pub fn set_assume_valid(mut self, hash: Sha256Hash) -> Self {
+        self.assume_valid = Some(hash);
+        self
+    }
+
+    pub fn assume_valid_height(&self) -> Option<u32> {
+        let assume_valid = self.assume_valid?;
+        self.checkpoints
+            .iter()
+            .find(|(_, hash)| *hash == assume_valid)
+            .map(|(height, _)| *height)
+    }
+
+    pub fn build(&self) -> Block {
+        let mut coinbase = Transaction::new();
+        coinbase.time_stamp = self.time_stamp;
+        for output in &self.outputs {
+            coinbase.add_output(output.clone());
+        }
+
+        let mut block = Block::new();
+        block.header.time_stamp = self.time_stamp;
+        block.add(coinbase);
+        block.mine(self.difficulty);
+        block
+    }
+}
+
+impl BlockIter {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(0))?;
+
+        Ok(BlockIter {
+            reader,
+            offset: 0,
+            len,
+            done: false
+        })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn read_block(&mut self) -> Result<Block, BlockReadError> {
+        let mut size = [0u8; 4];
+        if self.offset + 4 > self.len {
+            return Err(BlockReadError::Truncated(self.offset));
+        }
+        self.reader.read_exact(&mut size).map_err(BlockReadError::Io)?;
+        let size = u32::from_le_bytes(size);
+
+        if self.offset + 8 + size as u64 > self.len {
+            return Err(BlockReadError::Truncated(self.offset));
+        }
+        let mut buffer = vec![0; size as usize];
+        self.reader.read_exact(&mut buffer).map_err(BlockReadError::Io)?;
+
+        let mut suffix = [0u8; 4];
+        self.reader.read_exact(&mut suffix).map_err(BlockReadError::Io)?;
+        if u32::from_le_bytes(suffix) != size {
+            return Err(BlockReadError::Corrupt(self.offset));
+        }
+
+        let block = bincode::deserialize(&buffer)
+            .map_err(|_| BlockReadError::Corrupt(self.offset))?;
+        self.offset += 8 + size as u64;
+        Ok(block)
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = Result<Block, BlockReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.len {
+            return None;
+        }
+
+        let res = self.read_block();
+        self.done = res.is_err();
+        Some(res)
+    }
+}
+
+impl BlockRevIter {
+    pub fn new(file: File) -> io::Result<Self> {
+        let offset = file.metadata()?.len();
+
+        Ok(BlockRevIter {
+            reader: BufReader::new(file),
+            offset,
+            done: false
+        })
+    }
+
+    fn read_block(&mut self) -> Result<Block, BlockReadError> {
+        if self.offset < 8 {
+            return Err(BlockReadError::Truncated(self.offset));
+        }
+
+        let mut size = [0u8; 4];
+        self.reader
+            .seek(SeekFrom::Start(self.offset - 4))
+            .and_then(|_| self.reader.read_exact(&mut size))
+            .map_err(BlockReadError::Io)?;
+        let size = u32::from_le_bytes(size) as u64;
+        if self.offset < 8 + size {
+            return Err(BlockReadError::Corrupt(self.offset));
+        }
+
+        let start = self.offset - 8 - size;
+        let mut prefix = [0u8; 4];
+        let mut buffer = vec![0; size as usize];
+        self.reader
+            .seek(SeekFrom::Start(start))
+            .and_then(|_| self.reader.read_exact(&mut prefix))
+            .and_then(|_| self.reader.read_exact(&mut buffer))
+            .map_err(BlockReadError::Io)?;
+        if u32::from_le_bytes(prefix) as u64 != size {
+            return Err(BlockReadError::Corrupt(start));
+        }
+
+        let block = bincode::deserialize(&buffer)
+            .map_err(|_| BlockReadError::Corrupt(start))?;
+        self.offset = start;
+        Ok(block)
+    }
+}
+
+impl Iterator for BlockRevIter {
+    type Item = Result<Block, BlockReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset == 0 {
+            return None;
+        }
+
+        let res = self.read_block();
+        self.done = res.is_err();
+        Some(res)
+    }
+}
+
+pub fn retarget_difficulty(chain: &mut BufReader<File>, current: u32,
+        target_block_time: Duration, window: u32) -> u32 {
+
+    if window < 2 {
+        return current;
+    }
+
+    let start = chain.stream_position().unwrap();
+    let mut time_stamps = Vec::<SystemTime>::new();
+    while time_stamps.len() < window as usize {
+        match Block::from_file_backwads(&mut *chain) {
+            Some(block) => time_stamps.push(block.header.time_stamp),
+            None => break
+        }
+    }
+    chain.seek(SeekFrom::Start(start)).unwrap();
+
+    time_stamps.reverse();
+    retarget(current, &time_stamps, target_block_time, window)
+}
+
+pub fn is_retarget_height(height: u32) -> bool {
+    height > 0 && height.is_multiple_of(RETARGET_INTERVAL)
+}
+
+// The difficulty required of the block at `height`, derived from the chain
+// alone: it carries over from the parent except on retarget boundaries.
+// `previous_timestamps` is oldest first and ends with the parent.
+pub fn expected_difficulty(height: u32, parent_difficulty: u32,
+        previous_timestamps: &[SystemTime]) -> u32 {
+
+    if !is_retarget_height(height) {
+        return parent_difficulty;
+    }
+
+    let start = previous_timestamps.len()
+        .saturating_sub(RETARGET_INTERVAL as usize);
+    retarget(parent_difficulty, &previous_timestamps[start..],
+        TARGET_BLOCK_TIME, RETARGET_INTERVAL)
+}
+
+// Timestamps are oldest first. Fewer than `window` of them leave the
+// difficulty unchanged.
+pub fn retarget(current: u32, time_stamps: &[SystemTime],
+        target_block_time: Duration, window: u32) -> u32 {
+
+    if window < 2 || time_stamps.len() < window as usize {
+        return current;
+    }
+
+    let actual_time = time_stamps
+        .windows(2)
+        .map(|pair| pair[1].duration_since(pair[0]).unwrap_or(Duration::ZERO))
+        .sum::<Duration>();
+    let expected_time = target_block_time * (window - 1);
+
+    let adjustment = (expected_time.as_secs_f64() / actual_time.as_secs_f64())
+        .log2()
+        .clamp(-2.0, 2.0)
+        .round() as i64;
+
+    (current as i64 + adjustment).clamp(0, 256) as u32
+}
+
+fn apply_transaction(tx: &Transaction, utxo_set: &mut UTXOSet, height: u32)
+        -> Vec<((Sha256Hash, u32), UTXOEntry)> {
+
+    let tx_id = tx.calculate_id();
+    let is_coinbase = tx.is_coinbase();
+
+    let spent = tx.inputs()
+        .iter()
+        .filter_map(|input| {
+            let outpoint = (input.core.tx_id, input.core.output_id);
+            utxo_set.remove(&outpoint).map(|entry| (outpoint, entry))
+        })
+        .collect();
+    utxo_set.extend(tx.outputs().iter().enumerate().map(|(i, output)|
+        ((tx_id, i as u32), UTXOEntry {
+            output: output.clone(),
+            height,
+            is_coinbase
+        })
+    ));
+
+    spent
+}
+
+// Validates transactions in order, letting each one spend the outputs of
+// those before it. The UTXO set is only copied when that actually happens.
+fn validate_in_sequence(txs: &[&Transaction], utxo_set: &UTXOSet,
+        height: u32, chain_id: u32, verify_signatures: bool)
+        -> Vec<Result<u32, TransactionValidityError>> {
+
+    let tx_ids = txs
+        .iter()
+        .map(|tx| tx.calculate_id())
+        .collect::<HashSet<_>>();
+    let spends_in_sequence = txs
+        .iter()
+        .flat_map(|tx| tx.inputs())
+        .any(|input| tx_ids.contains(&input.core.tx_id));
+
+    let mut working_set = Cow::Borrowed(utxo_set);
+    let mut results = Vec::new();
+    for tx in txs {
+        let res = tx.validate(&working_set, height, chain_id,
+            verify_signatures);
+        if res.is_ok() && spends_in_sequence {
+            apply_transaction(tx, working_set.to_mut(), height);
+        }
+        results.push(res);
+    }
+
+    results
+}
+
+pub fn block_subsidy(height: u32, initial: u32, halving_interval: u32) -> u32 {
+    if halving_interval == 0 {
+        return initial;
+    }
+
+    initial.checked_shr(height / halving_interval).unwrap_or(0)
+}
+
+pub fn merkle_root(tx_ids: &[Sha256Hash]) -> Sha256Hash {
+    if tx_ids.is_empty() {
+        return [0u8; 32];
+    }
+
+    let mut level = tx_ids.to_vec();
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+
+        level = level
+            .chunks(2)
+            .map(|pair| merkle_parent(&pair[0], &pair[1]))
+            .collect();
+    }
+
+    level[0]
+}
+
+pub fn verify_merkle_proof(tx_id: &Sha256Hash, proof: &[(Sha256Hash, bool)],
+        merkle_root: &Sha256Hash) -> bool {
+
+    let hash = proof.iter().fold(*tx_id, |acc, (sibling, sibling_on_left)| {
+        if *sibling_on_left {
+            merkle_parent(sibling, &acc)
+        } else {
+            merkle_parent(&acc, sibling)
+        }
+    });
+
+    hash == *merkle_root
+}
+
+fn merkle_parent(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Merkle root over the sorted hashes of every (outpoint, entry) pair, so the
+// result does not depend on the UTXO set's iteration order.
+pub fn utxo_commitment(utxo_set: &UTXOSet) -> Sha256Hash {
+    let mut leaves = utxo_set
+        .iter()
+        .map(|entry| Sha256::digest(bincode::serialize(&entry).unwrap()).into())
+        .collect::<Vec<Sha256Hash>>();
+    leaves.sort_unstable();
+
+    merkle_root(&leaves)
+}
+
+pub fn chain_work(difficulty_sequence: &[u32]) -> u128 {
+    difficulty_sequence
+        .iter()
+        .map(|difficulty| 1u128.checked_shl(*difficulty).unwrap_or(u128::MAX))
+        .fold(0, |acc, work| acc.saturating_add(work))
+}
+
+// Bits are compared most significant first, so comparing a hash against an
+// all-zero base checks that the hash has n leading zero bits.
 fn are_first_n_bits_equal(slice1: &[u8], slice2: &[u8], n: usize) -> bool {
     let full_bytes = n / 8;
 
     let remaining_bits = n % 8;
+    let needed_bytes = full_bytes + (remaining_bits > 0) as usize;
 
-    if slice1.len() < full_bytes || slice2.len() < full_bytes {
+    if slice1.len() < needed_bytes || slice2.len() < needed_bytes {
         return false;
     }
     if slice1[..full_bytes] != slice2[..full_bytes] {
@@ -298,7 +1210,7 @@ fn are_first_n_bits_equal(slice1: &[u8], slice2: &[u8], n: usize) -> bool {
     }
 
     if remaining_bits > 0 {
-        let mask = (1u8 << remaining_bits) - 1;
+        let mask = !(0xffu8 >> remaining_bits);
         let last_byte1 = slice1[full_bytes] & mask;
         let last_byte2 = slice2[full_bytes] & mask;
         return last_byte1 == last_byte2;
@@ -307,3 +1219,356 @@ fn are_first_n_bits_equal(slice1: &[u8], slice2: &[u8], n: usize) -> bool {
     true
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testutil::{fund, make_keypair, spend, TempDir};
+
+    const DIFFICULTY: u32 = 4;
+    const REWARD: u32 = 50;
+
+    fn coinbase_block(height: u32) -> Block {
+        let (_, miner) = make_keypair();
+        let mut block = Block::new();
+        block.add_coinbase(&miner, REWARD, &UTXOSet::new(), height,
+            DEFAULT_CHAIN_ID);
+        block.mine(DIFFICULTY);
+        block
+    }
+
+    fn check(block: &Block, height: u32) -> Result<(), BlockValidityError> {
+        block.is_valid_block(DIFFICULTY, REWARD, &UTXOSet::new(), height,
+            DEFAULT_CHAIN_ID)
+    }
+
+    #[test]
+    fn coinbase_locktime_must_match_height() {
+        let mut block = coinbase_block(7);
+        assert!(check(&block, 7).is_ok());
+
+        block.tx_list[0].set_locktime(6);
+        block.mine(DIFFICULTY);
+        assert!(matches!(check(&block, 7),
+            Err(BlockValidityError::InvalidCoinbaseHeight)));
+    }
+
+    #[test]
+    fn repeated_coinbase_is_rejected_at_a_later_height() {
+        let block = coinbase_block(7);
+        let mut repeat = Block::new();
+        repeat.add(block.tx_list[0].clone());
+        repeat.mine(DIFFICULTY);
+
+        assert_eq!(repeat.tx_list[0].calculate_id(),
+            block.tx_list[0].calculate_id());
+        assert!(matches!(check(&repeat, 8),
+            Err(BlockValidityError::InvalidCoinbaseHeight)));
+    }
+
+    #[test]
+    fn rewind_drops_outputs_spent_within_the_block() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+
+        let parent = spend(&key, funded, &[(pubkey, 90)]);
+        let child = spend(&key, (parent.calculate_id(), 0), &[(pubkey, 80)]);
+        let mut block = Block::new();
+        block.add(parent);
+        block.add(child);
+        block.add_coinbase(&pubkey, REWARD, &utxo_set, 1, DEFAULT_CHAIN_ID);
+        block.apply(&mut utxo_set, 1);
+
+        let mut pending = HashSet::new();
+        block.rewind(&mut utxo_set, &mut pending);
+
+        assert!(utxo_set.is_empty());
+        assert_eq!(pending, HashSet::from([funded]));
+    }
+
+    #[test]
+    fn high_fee_child_pulls_its_parent_into_the_block() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let (_, other) = fund(&mut utxo_set, &pubkey, 100);
+
+        let parent = spend(&key, funded, &[(pubkey, 99)]);
+        let child = spend(&key, (parent.calculate_id(), 0), &[(pubkey, 49)]);
+        let unrelated = spend(&key, other, &[(pubkey, 90)]);
+
+        let assemble = |mempool: &HashSet<Transaction>, max_bytes| {
+            Block::from_mempool_with_limit(mempool, &utxo_set, &pubkey,
+                REWARD, 1, DEFAULT_CHAIN_ID, max_bytes)
+        };
+        let size = |tx: &Transaction| bincode::serialized_size(tx).unwrap();
+        let max_bytes = bincode::serialized_size(&assemble(&HashSet::new(),
+            usize::MAX)).unwrap() + size(&parent) + size(&child);
+
+        let mempool = HashSet::from([parent.clone(), child.clone(),
+            unrelated.clone()]);
+        let block = assemble(&mempool, max_bytes as usize);
+
+        assert_eq!(block.tx_list[1..], [parent, child]);
+    }
+
+    #[test]
+    fn assembled_block_places_parents_before_children() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let (_, other) = fund(&mut utxo_set, &pubkey, 100);
+
+        // Each descendant pays a higher fee rate than its ancestors.
+        let parent = spend(&key, funded, &[(pubkey, 99)]);
+        let child = spend(&key, (parent.calculate_id(), 0), &[(pubkey, 90)]);
+        let grandchild = spend(&key, (child.calculate_id(), 0),
+            &[(pubkey, 50)]);
+        let unrelated = spend(&key, other, &[(pubkey, 95)]);
+
+        let mempool = HashSet::from([grandchild.clone(), unrelated,
+            child.clone(), parent.clone()]);
+        let block = Block::from_mempool(&mempool, &utxo_set, &pubkey, REWARD,
+            1, DEFAULT_CHAIN_ID);
+        let position = |tx: &Transaction| block.tx_list
+            .iter()
+            .position(|included| included == tx)
+            .unwrap();
+
+        assert_eq!(block.tx_list.len(), 5);
+        assert!(position(&parent) > 0);
+        assert!(position(&parent) < position(&child));
+        assert!(position(&child) < position(&grandchild));
+    }
+
+    fn block_paying(fees: &[u32]) -> (Block, UTXOSet) {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let mut block = Block::new();
+        for fee in fees {
+            let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+            block.add(spend(&key, funded, &[(pubkey, 100 - fee)]));
+        }
+        (block, utxo_set)
+    }
+
+    #[test]
+    fn removing_lowest_fee_returns_remaining_minimum() {
+        for fees in [[1, 2, 3, 4], [4, 3, 2, 1], [3, 1, 4, 2]] {
+            let (mut block, utxo_set) = block_paying(&fees);
+
+            let mut remaining = fees.to_vec();
+            remaining.sort();
+            for _ in 0..fees.len() {
+                remaining.remove(0);
+                assert_eq!(block.remove_lowest_fee_transaction(&utxo_set, 1,
+                    DEFAULT_CHAIN_ID), remaining.first().copied());
+            }
+            assert!(block.tx_list.is_empty());
+        }
+    }
+
+    #[test]
+    fn written_block_reads_back_with_the_same_hash() {
+        let dir = TempDir::new();
+        let path = dir.path().join("chain");
+        let first = coinbase_block(0);
+        let second = coinbase_block(1);
+
+        let mut file = File::create(&path).unwrap();
+        first.write_to_file(&mut file);
+        second.write_to_file(&mut file);
+        drop(file);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let size = bincode::serialized_size(&first).unwrap() as u32;
+        assert_eq!(bytes[..4], size.to_le_bytes());
+
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        assert_eq!(Block::from_file(&mut reader).unwrap().hash(),
+            first.hash());
+        assert_eq!(Block::from_file(&mut reader).unwrap().hash(),
+            second.hash());
+
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        reader.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(Block::from_file_backwads(&mut reader).unwrap().hash(),
+            second.hash());
+    }
+
+    #[test]
+    fn json_view_round_trips_to_the_same_bincode() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let mut block = Block::new();
+        block.add(spend(&key, funded, &[(pubkey, 90)]));
+        block.add_coinbase(&pubkey, REWARD, &utxo_set, 1, DEFAULT_CHAIN_ID);
+        block.mine(DIFFICULTY);
+
+        let bytes = bincode::serialize(&block).unwrap();
+        let decoded: Block = bincode::deserialize(&bytes).unwrap();
+        let json = decoded.to_json();
+        let reencoded = bincode::serialize(&Block::from_json(&json).unwrap())
+            .unwrap();
+        assert_eq!(reencoded, bytes);
+    }
+
+    #[test]
+    fn timestamp_must_pass_the_median_and_stay_near_now() {
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+        let previous = (1..=MEDIAN_TIME_SPAN as u32)
+            .map(|i| now - minute * (20 - i))
+            .collect::<Vec<_>>();
+        let median = previous[MEDIAN_TIME_SPAN / 2];
+        let mut block = coinbase_block(1);
+
+        block.header.time_stamp = median;
+        assert!(matches!(block.is_valid_timestamp(&previous, now),
+            Err(BlockValidityError::TimestampTooOld)));
+
+        block.header.time_stamp = median + Duration::from_secs(1);
+        assert!(block.is_valid_timestamp(&previous, now).is_ok());
+
+        block.header.time_stamp = now + MAX_FUTURE_BLOCK_TIME + minute;
+        assert!(matches!(block.is_valid_timestamp(&previous, now),
+            Err(BlockValidityError::TimestampTooFarInFuture)));
+    }
+
+    #[test]
+    fn difficulty_counts_leading_zero_bits() {
+        let zero = [0u8; 32];
+        let hash_with = |first: u8, second: u8| {
+            let mut hash = [0xffu8; 32];
+            hash[0] = first;
+            hash[1] = second;
+            hash
+        };
+
+        // 7, 8 and 9 leading zero bits.
+        let seven = hash_with(0x01, 0xff);
+        let eight = hash_with(0x00, 0x80);
+        let nine = hash_with(0x00, 0x7f);
+
+        for (hash, leading) in [(seven, 7), (eight, 8), (nine, 9)] {
+            for difficulty in 7..=9 {
+                assert_eq!(are_first_n_bits_equal(&zero, &hash, difficulty),
+                    difficulty <= leading);
+            }
+        }
+
+        // Zero low bits do not count towards the difficulty.
+        let high_bit = hash_with(0x80, 0x00);
+        assert!(!are_first_n_bits_equal(&zero, &high_bit, 7));
+    }
+
+    #[test]
+    fn oversize_block_is_rejected() {
+        let mut tx = Transaction::new();
+        for _ in 0..100 {
+            tx.add_output(Output::new()
+                .set_pubkey_hash([0u8; 20])
+                .set_amount(1)
+                .collect());
+        }
+        let tx_size = bincode::serialized_size(&tx).unwrap() as usize;
+
+        // Size is checked before anything else about the transactions.
+        let mut block = coinbase_block(1);
+        for _ in 0..MAX_BLOCK_SIZE / tx_size + 1 {
+            block.add(tx.clone());
+        }
+
+        assert!(bincode::serialized_size(&block).unwrap()
+            > MAX_BLOCK_SIZE as u64);
+        assert!(matches!(check(&block, 1),
+            Err(BlockValidityError::BlockTooLarge)));
+    }
+
+    #[test]
+    fn block_needs_exactly_one_leading_coinbase() {
+        let mut empty = Block::new();
+        empty.mine(DIFFICULTY);
+        assert!(matches!(check(&empty, 1),
+            Err(BlockValidityError::MissingCoinbase)));
+
+        let mut doubled = coinbase_block(1);
+        doubled.add(coinbase_block(1).tx_list.remove(0));
+        doubled.mine(DIFFICULTY);
+        assert!(matches!(check(&doubled, 1),
+            Err(BlockValidityError::MultipleCoinbase)));
+
+        let (key, pubkey) = make_keypair();
+        let (_, funded) = fund(&mut UTXOSet::new(), &pubkey, 100);
+        let mut misplaced = coinbase_block(1);
+        misplaced.tx_list.insert(0, spend(&key, funded, &[(pubkey, 90)]));
+        misplaced.mine(DIFFICULTY);
+        assert!(matches!(check(&misplaced, 1),
+            Err(BlockValidityError::MisplacedCoinbase)));
+    }
+
+    #[test]
+    fn subsidy_halves_at_each_interval_boundary() {
+        assert_eq!(block_subsidy(0, 50, 10), 50);
+        assert_eq!(block_subsidy(9, 50, 10), 50);
+        assert_eq!(block_subsidy(10, 50, 10), 25);
+        assert_eq!(block_subsidy(19, 50, 10), 25);
+        assert_eq!(block_subsidy(20, 50, 10), 12);
+        assert_eq!(block_subsidy(59, 50, 10), 1);
+        assert_eq!(block_subsidy(60, 50, 10), 0);
+        assert_eq!(block_subsidy(u32::MAX, 50, 1), 0);
+        assert_eq!(block_subsidy(u32::MAX, 50, 0), 50);
+
+        for height in [HALVING_INTERVAL - 1, HALVING_INTERVAL] {
+            let block = coinbase_block(height);
+            let claimed = block.tx_list[0]
+                .outputs()
+                .iter()
+                .map(|output| output.amount())
+                .sum::<u32>();
+            assert_eq!(claimed, block_subsidy(height, REWARD,
+                HALVING_INTERVAL));
+            assert!(check(&block, height).is_ok());
+        }
+    }
+
+    #[test]
+    fn fees_near_u32_max_overflow_the_reward() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let mut block = Block::new();
+        for _ in 0..2 {
+            let (_, funded) = fund(&mut utxo_set, &pubkey, u32::MAX);
+            block.add(spend(&key, funded, &[(pubkey, 1)]));
+        }
+        block.add_coinbase(&pubkey, REWARD, &utxo_set, 1, DEFAULT_CHAIN_ID);
+        block.mine(DIFFICULTY);
+
+        assert!(matches!(block.is_valid_block(DIFFICULTY, REWARD, &utxo_set,
+            1, DEFAULT_CHAIN_ID), Err(BlockValidityError::RewardOverflow)));
+    }
+
+    #[test]
+    fn utxo_commitment_ignores_insertion_order() {
+        let (_, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        for amount in 1..=16 {
+            fund(&mut utxo_set, &pubkey, amount);
+        }
+
+        let mut entries = utxo_set.clone().into_iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(outpoint, _)| *outpoint);
+        let ascending = entries.iter().cloned().collect::<UTXOSet>();
+        let descending = entries.iter().rev().cloned().collect::<UTXOSet>();
+
+        let commitment = utxo_commitment(&utxo_set);
+        assert_eq!(utxo_commitment(&ascending), commitment);
+        assert_eq!(utxo_commitment(&descending), commitment);
+
+        let mut spent = utxo_set.clone();
+        spent.remove(&entries[0].0);
+        assert_ne!(utxo_commitment(&spent), commitment);
+    }
+}
@@ -0,0 +1,112 @@
+use std::f64::consts::LN_2;
+
+use k256::{
+    ecdsa::VerifyingKey,
+    sha2::{Digest, Sha256}
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::address::pubkey_hash;
+use super::transaction::{OutputLock, Sha256Hash, Transaction};
+
+
+pub const MAX_FILTER_SIZE: usize = 36_000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+    tweak: u32
+}
+
+impl BloomFilter {
+    // Sized with the usual m = -n ln(p) / ln(2)^2 and k = m / n ln(2), then
+    // clamped to what a node is willing to accept.
+    pub fn new(expected_items: usize, fp_rate: f64) -> Self {
+        let items = expected_items.max(1) as f64;
+        let bit_count = -items * fp_rate.ln() / (LN_2 * LN_2);
+        let size = ((bit_count / 8.0).ceil() as usize)
+            .clamp(1, MAX_FILTER_SIZE);
+        let hash_count = ((size * 8) as f64 / items * LN_2).round() as u32;
+
+        BloomFilter {
+            bits: vec![0; size],
+            hash_count: hash_count.clamp(1, MAX_HASH_FUNCS),
+            tweak: OsRng.next_u32()
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.bits.is_empty()
+            && self.bits.len() <= MAX_FILTER_SIZE
+            && (1..=MAX_HASH_FUNCS).contains(&self.hash_count)
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        for index in self.bit_indexes(data) {
+            self.bits[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn contains(&self, data: &[u8]) -> bool {
+        self.bit_indexes(data)
+            .all(|index| self.bits[index / 8] & (1 << (index % 8)) != 0)
+    }
+
+    // Covers outputs locked to the key itself as well as to its hash.
+    pub fn insert_pubkey(&mut self, pubkey: &VerifyingKey) {
+        self.insert(pubkey.to_encoded_point(true).as_bytes());
+        self.insert(&pubkey_hash(pubkey));
+    }
+
+    pub fn insert_outpoint(&mut self, tx_id: &Sha256Hash, output_id: u32) {
+        self.insert(&outpoint_bytes(tx_id, output_id));
+    }
+
+    // A transaction matches on its id, on any output lock or on any spent
+    // outpoint. Outpoints of matching outputs are added to the filter so the
+    // transaction that later spends them matches too.
+    pub fn matches_transaction(&mut self, tx: &Transaction) -> bool {
+        let tx_id = tx.calculate_id();
+        let mut matched = self.contains(&tx_id);
+
+        for (output_id, output) in tx.outputs().iter().enumerate() {
+            let is_match = match output.lock() {
+                OutputLock::Pubkey(pubkey) =>
+                    self.contains(pubkey.to_encoded_point(true).as_bytes()),
+                OutputLock::PubkeyHash(hash) => self.contains(hash)
+            };
+
+            if is_match {
+                self.insert_outpoint(&tx_id, output_id as u32);
+                matched = true;
+            }
+        }
+
+        matched || tx.inputs().iter().any(|input|
+            self.contains(&outpoint_bytes(&input.core.tx_id,
+                input.core.output_id)))
+    }
+
+    // Double hashing: the i-th index is h1 + i * h2 over the filter's bits.
+    fn bit_indexes(&self, data: &[u8]) -> impl Iterator<Item = usize> {
+        let digest = Sha256::new()
+            .chain_update(self.tweak.to_le_bytes())
+            .chain_update(data)
+            .finalize();
+        let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap());
+        let bit_count = self.bits.len() as u64 * 8;
+
+        (0..self.hash_count as u64).map(move |i|
+            (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+}
+
+fn outpoint_bytes(tx_id: &Sha256Hash, output_id: u32) -> Vec<u8> {
+    let mut bytes = tx_id.to_vec();
+    bytes.extend(output_id.to_le_bytes());
+    bytes
+}
@@ -1,60 +1,332 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::{File, OpenOptions, self},
-    io::{Seek, SeekFrom, Write},
-    sync::Mutex
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex, RwLock},
+    time::{Duration, SystemTime}
 };
 
+use k256::ecdsa::VerifyingKey;
+use log::{debug, error, info, warn};
 use serde::{Serialize, Deserialize};
 
-use super::transaction::{Sha256Hash, Transaction, UTXOSet};
+use super::block::{
+    block_subsidy,
+    chain_work,
+    expected_difficulty,
+    is_retarget_height,
+    retarget_difficulty,
+    utxo_commitment,
+    Block,
+    BlockHeader,
+    BlockIter,
+    BlockReadError,
+    BlockRevIter,
+    BlockValidityError,
+    GenesisConfig,
+    HALVING_INTERVAL,
+    MAX_BLOCK_SIZE,
+    MEDIAN_TIME_SPAN,
+    RETARGET_INTERVAL,
+    TARGET_BLOCK_TIME
+};
+use super::mempool::{dependency_order, Mempool, MempoolError};
+use super::metrics::Metrics;
+use super::orphans::OrphanPool;
+use super::transaction::{
+    short_hash,
+    Output,
+    Sha256Hash,
+    Transaction,
+    TransactionValidityError,
+    UTXODelta,
+    UTXOEntry,
+    UTXOSet
+};
+
+
+const MEMPOOL_MAX_COUNT: usize = 5000;
+const MEMPOOL_MAX_AGE: Duration = Duration::from_secs(72 * 60 * 60);
+const DEFAULT_MIN_FEE: u32 = 1;
+const DEFAULT_DUST_THRESHOLD: u32 = 1;
+const CHAIN_EXPORT_MAGIC: &[u8] = b"rustychain";
+const CHAIN_EXPORT_VERSION: u32 = 1;
+const JOURNAL_COMPACT_THRESHOLD: usize = 1000;
+const MAX_ORPHAN_BLOCKS: usize = 100;
+const MAX_SIDE_BLOCKS: usize = 100;
+const FEE_ESTIMATE_BLOCKS: usize = 20;
+const FEE_ESTIMATE_MIN_SAMPLES: usize = 5;
+pub const DEFAULT_DATA_DIR: &str = "./.state";
+pub const MAX_REORG_DEPTH: u32 = 100;
 
+type LegacyUTXOSet = HashMap<(Sha256Hash, u32), LegacyOutput>;
+pub type TxIndex = HashMap<Sha256Hash, (Sha256Hash, u32)>;
+// Block offsets by hash and by height, transaction heights, and the error
+// that stopped the scan if it did not reach the end of the file.
+type ChainIndex = (HashMap<Sha256Hash, u64>, Vec<u64>,
+    HashMap<Sha256Hash, u32>, Option<BlockReadError>);
+
+#[derive(Deserialize)]
+struct LegacyOutput {
+    to_pubkey: VerifyingKey,
+    amount: u32
+}
 
 pub struct StateWithFile<T>
     where T: Serialize + for <'a> Deserialize<'a>
 {
-    file: File,
-    state: T
+    path: PathBuf,
+    state: T,
+    journal_len: usize
+}
+
+pub trait Journaled {
+    type Delta: Serialize + for <'a> Deserialize<'a>;
+
+    fn apply_delta(&mut self, delta: &Self::Delta);
+}
+
+impl Journaled for UTXOSet {
+    type Delta = UTXODelta;
+
+    fn apply_delta(&mut self, delta: &UTXODelta) {
+        match delta {
+            UTXODelta::Insert(outpoint, entry) => {
+                self.insert(*outpoint, entry.clone());
+            }
+            UTXODelta::Remove(outpoint) => {
+                self.remove(outpoint);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum TxIndexDelta {
+    Insert(Sha256Hash, (Sha256Hash, u32)),
+    Remove(Sha256Hash)
+}
+
+impl Journaled for TxIndex {
+    type Delta = TxIndexDelta;
+
+    fn apply_delta(&mut self, delta: &TxIndexDelta) {
+        match delta {
+            TxIndexDelta::Insert(tx_id, location) => {
+                self.insert(*tx_id, *location);
+            }
+            TxIndexDelta::Remove(tx_id) => {
+                self.remove(tx_id);
+            }
+        }
+    }
 }
 
 impl<T> StateWithFile<T>
     where T: Serialize + for <'a> Deserialize<'a>
 {
-    pub fn new(file: &str, state: T) -> Self {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file)
-            .unwrap();
+    pub fn new(file: &Path, state: T) -> Result<Self, StateError> {
+        let mut state_with_file = StateWithFile {
+            path: file.to_path_buf(),
+            state,
+            journal_len: 0
+        };
 
-        file.seek(SeekFrom::Start(0)).unwrap();
+        let path = state_with_file.path.clone();
+        if let Some(val) = load_state(&path).map_err(StateError::io(&path))? {
+            state_with_file.state = val;
+            return Ok(state_with_file);
+        }
 
-        let mut state = state;
-        if let Ok(val) = bincode::deserialize_from(&mut file) {
-            state = val;
-        } else {
-            let serialized_state = bincode::serialize(&state).unwrap();
-            file.write_all(&serialized_state).unwrap();
+        let tmp_path = state_with_file.path_with_suffix(".tmp");
+        if let Some(val) = load_state(&tmp_path)
+                .map_err(StateError::io(&tmp_path))? {
+            state_with_file.state = val;
+            state_with_file.persist().map_err(StateError::io(&path))?;
+            return Ok(state_with_file);
         }
 
-        StateWithFile {
-            file,
-            state
+        if path.exists() {
+            let corrupt_path = state_with_file.path_with_suffix(".corrupt");
+            error!("[CORRUPT STATE][{}]", corrupt_path.display());
+            fs::rename(&path, &corrupt_path).map_err(StateError::io(&path))?;
         }
+
+        state_with_file.persist().map_err(StateError::io(&path))?;
+        Ok(state_with_file)
     }
 
     pub fn set_state(&mut self, new_state: T) {
         self.state = new_state;
         self.update();
+        self.clear_journal();
     }
 
     pub fn update(&mut self) {
-        self.file.seek(SeekFrom::Start(0)).unwrap();
-        self.file.set_len(0).unwrap();
+        self.persist().unwrap();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let tmp_path = self.path_with_suffix(".tmp");
         let serialized_state = bincode::serialize(&self.state).unwrap();
-        self.file.write_all(&serialized_state).unwrap();
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serialized_state)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    fn path_with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn clear_journal(&mut self) {
+        match fs::remove_file(self.path_with_suffix(".journal")) {
+            Err(err) if err.kind() != ErrorKind::NotFound =>
+                panic!("Failed to clear journal: {err}"),
+            _ => self.journal_len = 0
+        }
+    }
+}
+
+impl<T> StateWithFile<T>
+    where T: Serialize + for <'a> Deserialize<'a> + Journaled
+{
+    pub fn new_journaled(file: &Path, state: T) -> Result<Self, StateError> {
+        let mut state_with_file = StateWithFile::new(file, state)?;
+
+        let journal_path = state_with_file.path_with_suffix(".journal");
+        let (records, valid_len) = read_journal::<T::Delta>(&journal_path)
+            .map_err(StateError::io(&journal_path))?;
+        for deltas in &records {
+            for delta in deltas {
+                state_with_file.state.apply_delta(delta);
+            }
+        }
+        state_with_file.journal_len = records.len();
+
+        if let Some(valid_len) = valid_len {
+            warn!("[TRUNCATED JOURNAL][{}]", journal_path.display());
+            OpenOptions::new()
+                .write(true)
+                .open(&journal_path)
+                .and_then(|journal| journal.set_len(valid_len))
+                .map_err(StateError::io(&journal_path))?;
+        }
+
+        debug!("[REPLAYED JOURNAL][{}][{}]", journal_path.display(),
+            records.len());
+        Ok(state_with_file)
+    }
+
+    pub fn apply_delta(&mut self, deltas: Vec<T::Delta>) {
+        for delta in &deltas {
+            self.state.apply_delta(delta);
+        }
+
+        let record = bincode::serialize(&deltas).unwrap();
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path_with_suffix(".journal"))
+            .unwrap();
+        journal.write_all(&(record.len() as u32).to_le_bytes()).unwrap();
+        journal.write_all(&record).unwrap();
+        journal.sync_data().unwrap();
+
+        self.journal_len += 1;
+        if self.journal_len >= JOURNAL_COMPACT_THRESHOLD {
+            self.compact();
+        }
+    }
+
+    pub fn compact(&mut self) {
+        self.update();
+        self.clear_journal();
+        debug!("[COMPACTED STATE][{}]", self.path.display());
+    }
+}
+
+fn read_journal<D>(path: &Path) -> io::Result<(Vec<Vec<D>>, Option<u64>)>
+    where D: for <'a> Deserialize<'a>
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound =>
+            return Ok((Vec::new(), None)),
+        Err(err) => return Err(err)
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let mut record_len = [0u8; 4];
+        if reader.read_exact(&mut record_len).is_err() {
+            return Ok((records, Some(offset)));
+        }
+        let record_len = u32::from_le_bytes(record_len);
+
+        let mut record = vec![0u8; record_len as usize];
+        if reader.read_exact(&mut record).is_err() {
+            return Ok((records, Some(offset)));
+        }
+
+        match bincode::deserialize(&record) {
+            Ok(deltas) => records.push(deltas),
+            Err(_) => return Ok((records, Some(offset)))
+        }
+        offset += 4 + record_len as u64;
+    }
+
+    Ok((records, None))
+}
+
+fn load_state<T>(path: &Path) -> io::Result<Option<T>>
+    where T: for <'a> Deserialize<'a>
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err)
+    };
+
+    Ok(bincode::deserialize_from(BufReader::new(file)).ok())
+}
+
+fn migrate_legacy_utxo_set(file: &Path) -> Result<(), StateError> {
+    let path = file.to_path_buf();
+    if load_state::<UTXOSet>(&path).map_err(StateError::io(&path))?.is_some() {
+        return Ok(());
     }
+
+    let legacy_set = match load_state::<LegacyUTXOSet>(&path)
+            .map_err(StateError::io(&path))? {
+        Some(val) => val,
+        None => return Ok(())
+    };
+
+    info!("[MIGRATING UTXO SET][{}]", legacy_set.len());
+    let utxo_set = legacy_set
+        .into_iter()
+        .map(|(outpoint, output)| (outpoint, UTXOEntry {
+            output: Output::new()
+                .set_pubkey(output.to_pubkey)
+                .set_amount(output.amount)
+                .collect(),
+            height: 0,
+            is_coinbase: false
+        }))
+        .collect::<UTXOSet>();
+
+    StateWithFile { path: path.clone(), state: utxo_set, journal_len: 0 }
+        .persist()
+        .map_err(StateError::io(&path))
 }
 
 impl<T> std::ops::Deref for StateWithFile<T>
@@ -80,58 +352,1949 @@ pub struct GlobalState {
     pub block_height: Mutex<StateWithFile<u32>>,
     pub chain: Mutex<File>,
     pub utxo_set: Mutex<StateWithFile<UTXOSet>>,
-    pub mempool:  Mutex<StateWithFile<HashSet<Transaction>>>,
+    pub mempool:  Mutex<StateWithFile<Mempool>>,
     pub difficulty: Mutex<StateWithFile<u32>>,
     pub reward: Mutex<StateWithFile<u32>>,
-    pub previous_block_hash: Mutex<StateWithFile<Sha256Hash>>
+    pub previous_block_hash: Mutex<StateWithFile<Sha256Hash>>,
+    pub chain_work: Mutex<StateWithFile<u128>>,
+    pub min_fee: Mutex<StateWithFile<u32>>,
+    pub dust_threshold: Mutex<StateWithFile<u32>>,
+    pub pruned_height: Mutex<StateWithFile<u32>>,
+    block_index: Mutex<HashMap<Sha256Hash, u64>>,
+    // File offset of every main chain block, by height.
+    block_offsets: Mutex<Vec<u64>>,
+    tx_heights: Mutex<HashMap<Sha256Hash, u32>>,
+    txindex: Option<Mutex<StateWithFile<TxIndex>>>,
+    side_blocks: Mutex<OrphanPool>,
+    orphans: Mutex<OrphanPool>,
+    genesis_hash: Sha256Hash,
+    chain_id: u32,
+    checkpoints: HashMap<u32, Sha256Hash>,
+    assume_valid_height: Option<u32>,
+    max_reorg_depth: u32,
+    data_dir: PathBuf,
+    tx_relay: Mutex<Option<Sender<Sha256Hash>>>,
+    utxo_snapshot: RwLock<Arc<UTXOSet>>,
+    metrics: Metrics
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(PathBuf, io::Error),
+    CorruptChain(BlockReadError),
+    InvalidGenesis(BlockValidityError)
+}
+
+impl StateError {
+    fn io(path: &Path) -> impl FnOnce(io::Error) -> StateError + '_ {
+        move |err| StateError::Io(path.to_path_buf(), err)
+    }
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    AlreadyInMempool,
+    Conflict(Sha256Hash),
+    InvalidSignature(u32),
+    InputNotFound(u32),
+    InsufficientFee(u32),
+    FeeTooLow(u32),
+    DustOutput(u32),
+    InvalidTransaction(TransactionValidityError),
+    Rejected(MempoolError)
+}
+
+#[derive(Debug)]
+pub enum ReorgError {
+    TooDeep(u32),
+    InvalidBlock(BlockValidityError)
+}
+
+impl From<BlockValidityError> for ReorgError {
+    fn from(err: BlockValidityError) -> Self {
+        ReorgError::InvalidBlock(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    InvalidFormat,
+    UnsupportedVersion(u32),
+    InvalidBlock(u32, BlockValidityError)
+}
+
+#[derive(Debug)]
+pub enum ReindexError {
+    Read(BlockReadError),
+    PrunedHistory,
+    InvalidBlock(u32, BlockValidityError)
+}
+
+#[derive(Debug)]
+pub enum MineError {
+    StaleTip,
+    InvalidBlock(BlockValidityError)
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    InvalidBlock(u32, BlockValidityError),
+    SupplyMismatch(u64, u64)
+}
+
+#[derive(Debug)]
+pub enum BlockAcceptance {
+    Connected,
+    SideBranch,
+    Reorganized(u32),
+    Orphaned,
+    AlreadyKnown
 }
 
 impl GlobalState {
-    pub fn new() -> Self {
-        fs::create_dir_all("./.state").unwrap();
+    pub fn new() -> Result<Self, StateError> {
+        GlobalState::new_with_verification(false)
+    }
+
+    pub fn new_with_verification(verify_chain: bool)
+            -> Result<Self, StateError> {
+
+        GlobalState::with_genesis(GenesisConfig::new(), verify_chain, false)
+    }
+
+    pub fn with_data_dir(data_dir: &Path) -> Result<Self, StateError> {
+        GlobalState::open(data_dir, GenesisConfig::new(), false, false)
+    }
+
+    pub fn with_genesis(genesis: GenesisConfig, verify_chain: bool,
+            txindex: bool) -> Result<Self, StateError> {
+
+        GlobalState::open(Path::new(DEFAULT_DATA_DIR), genesis, verify_chain,
+            txindex)
+    }
+
+    pub fn open(data_dir: &Path, genesis: GenesisConfig, verify_chain: bool,
+            txindex: bool) -> Result<Self, StateError> {
+
+        fs::create_dir_all(data_dir).map_err(StateError::io(data_dir))?;
 
-        let block_height = StateWithFile::new("./.state/block_height", 0);
-        println!("[BLOCK HEIGHT][{}]", *block_height);
+        let block_height = StateWithFile::new(&data_dir.join("block_height"),
+            0)?;
+        debug!("[BLOCK HEIGHT][{}]", *block_height);
         let block_height = Mutex::new(block_height);
 
-        let chain = Mutex::new(OpenOptions::new()
+        let chain_path = data_dir.join("chain");
+        let chain = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("./.state/chain")
-            .unwrap()
-        );
+            .open(&chain_path)
+            .map_err(StateError::io(&chain_path))?;
+        let (block_index, block_offsets, tx_heights, read_error) =
+            index_chain(&chain);
+        let truncated = match read_error {
+            None => false,
+            Some(BlockReadError::Truncated(offset)) if is_torn_tail(&chain) => {
+                warn!("[TRUNCATED CHAIN][{}]", offset);
+                chain.set_len(offset)
+                    .and_then(|_| chain.sync_all())
+                    .map_err(StateError::io(&chain_path))?;
+                true
+            }
+            Some(err) => return Err(StateError::CorruptChain(err))
+        };
+        debug!("[BLOCK INDEX][{}]", block_index.len());
+        debug!("[TX INDEX][{}]", tx_heights.len());
+        let chain = Mutex::new(chain);
+        let block_index = Mutex::new(block_index);
+        let block_offsets = Mutex::new(block_offsets);
+        let tx_heights = Mutex::new(tx_heights);
+
+        migrate_legacy_utxo_set(&data_dir.join("utxo_set"))?;
         let utxo_set = UTXOSet::new();
-        let utxo_set = StateWithFile::new("./.state/utxo_set", utxo_set);
-        println!("[UTXO SET][{}]", utxo_set.len());
+        let utxo_set = StateWithFile::new_journaled(
+            &data_dir.join("utxo_set"), utxo_set)?;
+        debug!("[UTXO SET][{}]", utxo_set.len());
+        let utxo_snapshot = RwLock::new(Arc::new((*utxo_set).clone()));
         let utxo_set = Mutex::new(utxo_set);
 
-        let mempool = HashSet::<Transaction>::new();
-        let mempool = StateWithFile::new("./.state/mempool", mempool);
-        println!("[MEMPOOL][{}]", mempool.len());
+        let mempool = Mempool::new(MEMPOOL_MAX_COUNT, MEMPOOL_MAX_AGE);
+        let mempool = StateWithFile::new(&data_dir.join("mempool"), mempool)?;
+        debug!("[MEMPOOL][{}]", mempool.len());
         let mempool = Mutex::new(mempool);
 
-        let difficulty = StateWithFile::new("./.state/difficulty", 20);
-        println!("[DIFFICULTY][{}]", *difficulty);
+        let difficulty = StateWithFile::new(&data_dir.join("difficulty"),
+            genesis.difficulty())?;
+        debug!("[DIFFICULTY][{}]", *difficulty);
         let difficulty = Mutex::new(difficulty);
 
-        let reward = StateWithFile::new("./.state/reward", 10);
-        println!("[REWARD][{}]", *reward);
+        let reward = StateWithFile::new(&data_dir.join("reward"), 10)?;
+        debug!("[REWARD][{}]", *reward);
         let reward = Mutex::new(reward);
 
-        let previous_block_hash = StateWithFile::new("./.state/previous_hash",
-            [0u8; 32]);
+        let previous_block_hash = StateWithFile::new(
+            &data_dir.join("previous_hash"), [0u8; 32])?;
         let previous_block_hash = Mutex::new(previous_block_hash);
 
-        GlobalState {
+        let chain_work = StateWithFile::new(&data_dir.join("chain_work"), 0)?;
+        debug!("[CHAIN WORK][{}]", *chain_work);
+        let chain_work = Mutex::new(chain_work);
+
+        let min_fee = StateWithFile::new(&data_dir.join("min_fee"),
+            DEFAULT_MIN_FEE)?;
+        debug!("[MIN FEE][{}]", *min_fee);
+        let min_fee = Mutex::new(min_fee);
+
+        let dust_threshold = StateWithFile::new(
+            &data_dir.join("dust_threshold"), DEFAULT_DUST_THRESHOLD)?;
+        debug!("[DUST THRESHOLD][{}]", *dust_threshold);
+        let dust_threshold = Mutex::new(dust_threshold);
+
+        let pruned_height = StateWithFile::new(
+            &data_dir.join("pruned_height"), 0)?;
+        debug!("[PRUNED HEIGHT][{}]", *pruned_height);
+        let pruned_height = Mutex::new(pruned_height);
+
+        let txindex = if txindex {
+            let txindex = StateWithFile::new_journaled(
+                &data_dir.join("txindex"), TxIndex::new())?;
+            debug!("[TXINDEX][{}]", txindex.len());
+            Some(Mutex::new(txindex))
+        } else {
+            None
+        };
+
+        let mut state = GlobalState {
             block_height,
             chain,
             utxo_set,
             mempool,
             difficulty,
             reward,
-            previous_block_hash
+            previous_block_hash,
+            chain_work,
+            min_fee,
+            dust_threshold,
+            pruned_height,
+            block_index,
+            block_offsets,
+            tx_heights,
+            txindex,
+            side_blocks: Mutex::new(OrphanPool::new(MAX_SIDE_BLOCKS)),
+            orphans: Mutex::new(OrphanPool::new(MAX_ORPHAN_BLOCKS)),
+            genesis_hash: [0u8; 32],
+            chain_id: genesis.chain_id(),
+            checkpoints: genesis.checkpoints().iter().copied().collect(),
+            assume_valid_height: genesis.assume_valid_height(),
+            max_reorg_depth: MAX_REORG_DEPTH,
+            data_dir: data_dir.to_path_buf(),
+            tx_relay: Mutex::new(None),
+            utxo_snapshot,
+            metrics: Metrics::new()
+        };
+
+        let first_block = {
+            let chain = state.chain.lock().unwrap();
+            let mut reader = chain.try_clone()
+                .map(BufReader::new)
+                .map_err(StateError::io(&chain_path))?;
+            reader.seek(SeekFrom::Start(0))
+                .map_err(StateError::io(&chain_path))?;
+            Block::from_file(&mut reader)
+        };
+        state.genesis_hash = match first_block {
+            Some(block) => block.hash(),
+            None => state.insert_genesis(&genesis.build())
+                .map_err(StateError::InvalidGenesis)?
+        };
+        info!("[GENESIS][{}]", short_hash(&state.genesis_hash));
+        state.repair_tip(truncated);
+
+        if state.txindex.is_some() && !state.is_txindex_current() {
+            state.rebuild_txindex();
+        }
+
+        state.metrics.set_difficulty(**state.difficulty.lock().unwrap());
+        state.metrics.set_mempool_size(state.mempool.lock().unwrap().len());
+
+        if verify_chain {
+            match state.verify_chain(true) {
+                Ok(height) => info!("[CHAIN VERIFIED][{}]", height),
+                Err(VerifyError::InvalidBlock(height, err)) =>
+                    error!("[INVALID CHAIN][{}][{:?}]", height, err),
+                Err(VerifyError::SupplyMismatch(expected, actual)) =>
+                    error!("[SUPPLY MISMATCH][{}][{}]", expected, actual)
+            }
+        }
+
+        Ok(state)
+    }
+
+    pub fn utxo_snapshot(&self) -> Arc<UTXOSet> {
+        Arc::clone(&self.utxo_snapshot.read().unwrap())
+    }
+
+    fn publish_utxo_snapshot(&self, utxo_set: &UTXOSet) {
+        *self.utxo_snapshot.write().unwrap() = Arc::new(utxo_set.clone());
+    }
+
+    // Copy on write: the snapshot is only cloned while a reader still holds
+    // it, otherwise the deltas land in place.
+    fn apply_to_utxo_snapshot(&self, deltas: &[UTXODelta]) {
+        let mut snapshot = self.utxo_snapshot.write().unwrap();
+        let utxo_set = Arc::make_mut(&mut snapshot);
+        for delta in deltas {
+            utxo_set.apply_delta(delta);
+        }
+    }
+
+    // The confirmed UTXO set with every mempool transaction applied on top,
+    // so unconfirmed change can be spent. Outputs created in the mempool
+    // carry the next block height.
+    pub fn effective_utxo_set(&self) -> UTXOSet {
+        let mut utxo_set = (**self.utxo_set.lock().unwrap()).clone();
+        let mempool = self.mempool.lock().unwrap();
+        let height = **self.block_height.lock().unwrap();
+
+        for tx in dependency_order(mempool.iter()) {
+            let spendable = tx.inputs()
+                .iter()
+                .all(|input| utxo_set
+                    .contains_key(&(input.core.tx_id, input.core.output_id)));
+            if !spendable {
+                continue;
+            }
+
+            for input in tx.inputs() {
+                utxo_set.remove(&(input.core.tx_id, input.core.output_id));
+            }
+            let tx_id = tx.calculate_id();
+            utxo_set.extend(tx.outputs().iter().enumerate().map(|(i, output)|
+                ((tx_id, i as u32), UTXOEntry {
+                    output: output.clone(),
+                    height,
+                    is_coinbase: false
+                })
+            ));
+        }
+
+        utxo_set
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // The tip and the commitment to the UTXO set at that tip.
+    pub fn utxo_commitment(&self) -> (Sha256Hash, Sha256Hash) {
+        let utxo_set = self.utxo_set.lock().unwrap();
+        let tip = **self.previous_block_hash.lock().unwrap();
+
+        (tip, utxo_commitment(&utxo_set))
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn genesis_hash(&self) -> Sha256Hash {
+        self.genesis_hash
+    }
+
+    pub fn chain_id(&self) -> u32 {
+        self.chain_id
+    }
+
+    fn check_checkpoint(&self, block: &Block, height: u32)
+            -> Result<(), BlockValidityError> {
+
+        match self.checkpoints.get(&height) {
+            Some(hash) if *hash != block.hash() =>
+                Err(BlockValidityError::CheckpointMismatch),
+            _ => Ok(())
+        }
+    }
+
+    // Signatures below the assume-valid checkpoint are not checked. Anything
+    // else about those blocks still is, and the chain has to pass through
+    // every checkpoint to be accepted at all.
+    fn verify_signatures_at(&self, height: u32) -> bool {
+        self.assume_valid_height
+            .is_none_or(|assume_valid_height| height > assume_valid_height)
+    }
+
+    pub fn set_max_reorg_depth(mut self, depth: u32) -> Self {
+        self.max_reorg_depth = depth;
+        self
+    }
+
+    pub fn set_tx_relay(&self, relay: Sender<Sha256Hash>) {
+        *self.tx_relay.lock().unwrap() = Some(relay);
+    }
+
+    pub fn submit_transaction(&self, tx: Transaction)
+            -> Result<(), SubmitError> {
+
+        let dust_threshold = **self.dust_threshold.lock().unwrap();
+        if let Some(i) = tx.outputs()
+                .iter()
+                .position(|output| output.amount() < dust_threshold) {
+            return Err(SubmitError::DustOutput(i as u32));
+        }
+
+        let tx_id = tx.calculate_id();
+        {
+            let utxo_set = self.utxo_set.lock().unwrap();
+            let mut mempool = self.mempool.lock().unwrap();
+            let block_height = self.block_height.lock().unwrap();
+            let min_fee = **self.min_fee.lock().unwrap();
+
+            let height = **block_height;
+            if let Ok(fee) = mempool.validate(&tx, &utxo_set, height,
+                    self.chain_id) {
+                if fee < min_fee {
+                    return Err(SubmitError::FeeTooLow(fee));
+                }
+            }
+
+            mempool.add(tx, &utxo_set, height, self.chain_id).map_err(|err| {
+                match err {
+                    MempoolError::AlreadyInMempool =>
+                        SubmitError::AlreadyInMempool,
+                    MempoolError::Conflict(tx_id) =>
+                        SubmitError::Conflict(tx_id),
+                    MempoolError::InvalidTransaction(err) => match err {
+                        TransactionValidityError::InvalidSignature(i) =>
+                            SubmitError::InvalidSignature(i),
+                        TransactionValidityError::InputDoesNotExist(i) =>
+                            SubmitError::InputNotFound(i),
+                        TransactionValidityError::InvalidOutputAmount(diff) =>
+                            SubmitError::InsufficientFee(diff),
+                        err => SubmitError::InvalidTransaction(err)
+                    },
+                    err => SubmitError::Rejected(err)
+                }
+            })?;
+            mempool.update();
+            self.metrics.set_mempool_size(mempool.len());
+        }
+
+        debug!("[SUBMITTED TX][{}]", short_hash(&tx_id));
+        if let Some(relay) = &*self.tx_relay.lock().unwrap() {
+            let _ = relay.send(tx_id);
+        }
+
+        Ok(())
+    }
+
+    fn insert_genesis(&self, genesis: &Block)
+            -> Result<Sha256Hash, BlockValidityError> {
+
+        genesis.is_valid_genesis()?;
+
+        let mut chain = self.chain.lock().unwrap();
+        let mut utxo_set = self.utxo_set.lock().unwrap();
+        let mut block_height = self.block_height.lock().unwrap();
+        let mut previous_block_hash = self.previous_block_hash.lock().unwrap();
+        let mut total_work = self.chain_work.lock().unwrap();
+
+        let hash = genesis.hash();
+        let offset = chain.seek(SeekFrom::End(0)).unwrap();
+        genesis.write_to_file(&mut chain);
+        self.block_index.lock().unwrap().insert(hash, offset);
+        self.block_offsets.lock().unwrap().push(offset);
+        self.index_transactions(genesis, 0);
+
+        let mut genesis_utxos = UTXOSet::new();
+        genesis.apply(&mut genesis_utxos, 0);
+        utxo_set.set_state(genesis_utxos);
+        self.publish_utxo_snapshot(&utxo_set);
+        block_height.set_state(1);
+        previous_block_hash.set_state(hash);
+        total_work.set_state(chain_work(&[genesis.header.difficulty]));
+
+        info!("[INSERTED GENESIS][{}]", short_hash(&hash));
+        Ok(hash)
+    }
+
+    pub fn validate_transaction(&self, tx: &Transaction)
+            -> Result<u32, TransactionValidityError> {
+
+        let utxo_set = self.utxo_set.lock().unwrap();
+        let block_height = self.block_height.lock().unwrap();
+
+        tx.is_valid(&utxo_set, **block_height, self.chain_id)
+    }
+
+    pub fn validate_block(&self, block: &Block)
+            -> Result<(), BlockValidityError> {
+
+        let chain = self.chain.lock().unwrap();
+        let utxo_set = self.utxo_set.lock().unwrap();
+        let block_height = self.block_height.lock().unwrap();
+        let previous_block_hash = self.previous_block_hash.lock().unwrap();
+        let difficulty = **self.difficulty.lock().unwrap();
+        let reward = **self.reward.lock().unwrap();
+
+        let previous_timestamps = recent_timestamps(&chain, MEDIAN_TIME_SPAN);
+        self.check_checkpoint(block, **block_height)?;
+        block.validate_in_context(&previous_block_hash, &previous_timestamps,
+            difficulty, reward, &utxo_set, **block_height, self.chain_id,
+            self.verify_signatures_at(**block_height))
+    }
+
+    pub fn append_block(&self, block: &Block)
+            -> Result<(), BlockValidityError> {
+
+        let mut chain = self.chain.lock().unwrap();
+        let mut utxo_set = self.utxo_set.lock().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut block_height = self.block_height.lock().unwrap();
+        let mut previous_block_hash = self.previous_block_hash.lock().unwrap();
+        let mut total_work = self.chain_work.lock().unwrap();
+        let difficulty = **self.difficulty.lock().unwrap();
+        let reward = **self.reward.lock().unwrap();
+
+        let previous_timestamps = recent_timestamps(&chain, MEDIAN_TIME_SPAN);
+        self.check_checkpoint(block, **block_height)?;
+        block.validate_in_context(&previous_block_hash, &previous_timestamps,
+            difficulty, reward, &utxo_set, **block_height, self.chain_id,
+            self.verify_signatures_at(**block_height))?;
+
+        let offset = chain.seek(SeekFrom::End(0)).unwrap();
+        block.write_to_file(&mut chain);
+        self.block_index.lock().unwrap().insert(block.hash(), offset);
+        self.block_offsets.lock().unwrap().push(offset);
+        self.index_transactions(block, **block_height);
+
+        let deltas = block.utxo_deltas(**block_height);
+        self.apply_to_utxo_snapshot(&deltas);
+        utxo_set.apply_delta(deltas);
+        block.update_mempool(&mut mempool);
+        mempool.update();
+        self.metrics.set_mempool_size(mempool.len());
+        **block_height += 1;
+        block_height.update();
+        previous_block_hash.set_state(block.hash());
+        **total_work += chain_work(&[block.header.difficulty]);
+        total_work.update();
+        self.update_difficulty(difficulty_at(&chain, **block_height,
+            block.header.difficulty));
+
+        info!("[APPENDED BLOCK][{}][{}]", **block_height,
+            short_hash(&block.hash()));
+        Ok(())
+    }
+
+    // Mining runs without holding any state lock, so a block that arrives in
+    // the meantime makes the mined one stale rather than blocking the node.
+    pub fn mine_next_block(&self, miner_pubkey: &VerifyingKey)
+            -> Result<Block, MineError> {
+
+        let (mut block, difficulty) = {
+            let utxo_set = self.utxo_set.lock().unwrap();
+            let mempool = self.mempool.lock().unwrap();
+            let block_height = **self.block_height.lock().unwrap();
+            let parent = **self.previous_block_hash.lock().unwrap();
+            let difficulty = **self.difficulty.lock().unwrap();
+            let reward = **self.reward.lock().unwrap();
+
+            let mut block = Block::from_mempool(&mempool, &utxo_set,
+                miner_pubkey, reward, block_height, self.chain_id);
+            block.set_previous_block(&parent);
+            (block, difficulty)
+        };
+
+        block.mine(difficulty);
+        match self.append_block(&block) {
+            Ok(()) => {}
+            Err(BlockValidityError::WrongParent) => {
+                return Err(MineError::StaleTip);
+            }
+            Err(err) => return Err(MineError::InvalidBlock(err))
+        }
+        self.metrics.record_mined_block();
+
+        Ok(block)
+    }
+
+    // Reads only the requested blocks, starting at the offset index, and
+    // nothing at all past the tip.
+    pub fn headers(&self, from_height: u32, count: u32) -> Vec<BlockHeader> {
+        self.main_chain_blocks(from_height, from_height.saturating_add(count))
+            .into_iter()
+            .map(|block| block.header)
+            .collect()
+    }
+
+    pub fn blocks(&self)
+            -> impl Iterator<Item = Result<Block, BlockReadError>> {
+
+        let _chain = self.chain.lock().unwrap();
+        let (blocks, err) = match File::open(self.data_dir.join("chain"))
+                .and_then(BlockIter::new) {
+            Ok(blocks) => (Some(blocks), None),
+            Err(err) => (None, Some(BlockReadError::Io(err)))
+        };
+
+        err.into_iter().map(Err).chain(blocks.into_iter().flatten())
+    }
+
+    pub fn blocks_rev(&self)
+            -> impl Iterator<Item = Result<Block, BlockReadError>> {
+
+        let _chain = self.chain.lock().unwrap();
+        let (blocks, err) = match File::open(self.data_dir.join("chain"))
+                .and_then(BlockRevIter::new) {
+            Ok(blocks) => (Some(blocks), None),
+            Err(err) => (None, Some(BlockReadError::Io(err)))
+        };
+
+        err.into_iter().map(Err).chain(blocks.into_iter().flatten())
+    }
+
+    // Fee rates are in fee units per 1000 bytes of serialized transaction.
+    pub fn estimate_fee(&self, target_blocks: u32) -> u32 {
+        let min_fee = **self.min_fee.lock().unwrap();
+        let chain_height = **self.block_height.lock().unwrap();
+        let first_sampled = chain_height
+            .saturating_sub(FEE_ESTIMATE_BLOCKS as u32);
+        let blocks = self.main_chain_blocks(first_sampled, chain_height);
+
+        // Outputs created inside the sampled blocks are recorded in order,
+        // so a child is priced against a parent confirmed just before it.
+        let mut created = HashMap::<(Sha256Hash, u32), u32>::new();
+        let mut fee_rates = Vec::new();
+        for tx in blocks.iter().flat_map(|block| block.transactions()) {
+            let tx_id = tx.calculate_id();
+            if !tx.is_coinbase() {
+                let input_amount = tx.inputs()
+                    .iter()
+                    .map(|input| {
+                        let outpoint = (input.core.tx_id, input.core.output_id);
+                        created.get(&outpoint)
+                            .copied()
+                            .or_else(|| self.confirmed_output_amount(&outpoint))
+                            .map(u64::from)
+                    })
+                    .sum::<Option<u64>>();
+                let output_amount = tx.outputs()
+                    .iter()
+                    .map(|output| output.amount() as u64)
+                    .sum::<u64>();
+                let fee = input_amount
+                    .and_then(|amount| amount.checked_sub(output_amount));
+
+                if let Some(fee) = fee {
+                    let size = bincode::serialized_size(tx).unwrap();
+                    fee_rates.push((fee * 1000 / size.max(1)) as u32);
+                }
+            }
+
+            for (i, output) in tx.outputs().iter().enumerate() {
+                created.insert((tx_id, i as u32), output.amount());
+            }
+        }
+
+        if fee_rates.len() < FEE_ESTIMATE_MIN_SAMPLES {
+            return min_fee;
+        }
+
+        let percentile = match target_blocks {
+            0..=1 => 90,
+            2..=3 => 75,
+            4..=6 => 50,
+            _ => 25
+        };
+        fee_rates.sort_unstable();
+        let index = (fee_rates.len() - 1) * percentile / 100;
+        fee_rates[index].max(min_fee)
+    }
+
+    // Looks the funding transaction up through the height and offset
+    // indexes instead of replaying the chain.
+    fn confirmed_output_amount(&self, outpoint: &(Sha256Hash, u32))
+            -> Option<u32> {
+
+        let (tx_id, output_id) = outpoint;
+        let height = *self.tx_heights.lock().unwrap().get(tx_id)?;
+        self.main_chain_blocks(height, height + 1)
+            .pop()?
+            .transactions()
+            .iter()
+            .find(|tx| tx.calculate_id() == *tx_id)?
+            .outputs()
+            .get(*output_id as usize)
+            .map(|output| output.amount())
+    }
+
+    pub fn confirmations(&self, tx_id: &Sha256Hash) -> Option<u32> {
+        {
+            let block_height = self.block_height.lock().unwrap();
+            let tx_heights = self.tx_heights.lock().unwrap();
+            if let Some(height) = tx_heights.get(tx_id) {
+                return Some(**block_height - height);
+            }
+        }
+
+        self.mempool.lock().unwrap().get(tx_id).map(|_| 0)
+    }
+
+    pub fn find_transaction(&self, tx_id: &Sha256Hash)
+            -> Option<(Block, usize)> {
+
+        let (block_hash, index) = *self.txindex
+            .as_ref()?
+            .lock()
+            .unwrap()
+            .get(tx_id)?;
+
+        self.block(&block_hash).map(|block| (block, index as usize))
+    }
+
+    fn index_transactions(&self, block: &Block, height: u32) {
+        let mut tx_heights = self.tx_heights.lock().unwrap();
+        for tx in block.transactions() {
+            tx_heights.insert(tx.calculate_id(), height);
+        }
+
+        if let Some(txindex) = &self.txindex {
+            let block_hash = block.hash();
+            let deltas = block.transactions()
+                .iter()
+                .enumerate()
+                .map(|(i, tx)| TxIndexDelta::Insert(tx.calculate_id(),
+                    (block_hash, i as u32)))
+                .collect();
+            txindex.lock().unwrap().apply_delta(deltas);
+        }
+    }
+
+    fn unindex_transactions(&self, block: &Block) {
+        let mut tx_heights = self.tx_heights.lock().unwrap();
+        for tx in block.transactions() {
+            tx_heights.remove(&tx.calculate_id());
+        }
+
+        if let Some(txindex) = &self.txindex {
+            let deltas = block.transactions()
+                .iter()
+                .map(|tx| TxIndexDelta::Remove(tx.calculate_id()))
+                .collect();
+            txindex.lock().unwrap().apply_delta(deltas);
+        }
+    }
+
+    // The height counter and tip hash are persisted separately from the chain
+    // file, so a crash between writes can leave them stale. The chain file
+    // is authoritative. After any repair, including a truncated trailing
+    // block, the UTXO set is rebuilt since it may hold the effects of a
+    // block the chain file lost, or miss those of one it kept.
+    fn repair_tip(&self, truncated: bool) {
+        let mut repaired = truncated;
+
+        let block_index = self.block_index.lock().unwrap();
+        let chain_height = block_index.len() as u32;
+        let tip = match block_index
+                .iter()
+                .max_by_key(|(_, offset)| **offset) {
+            Some((hash, _)) => *hash,
+            None => return
+        };
+        drop(block_index);
+
+        let mut block_height = self.block_height.lock().unwrap();
+        if **block_height != chain_height {
+            warn!("[REPAIRED BLOCK HEIGHT][{}][{}]", **block_height,
+                chain_height);
+            block_height.set_state(chain_height);
+            repaired = true;
+        }
+        drop(block_height);
+
+        let mut previous_block_hash = self.previous_block_hash.lock().unwrap();
+        if **previous_block_hash != tip {
+            warn!("[REPAIRED TIP][{}][{}]", short_hash(&previous_block_hash),
+                short_hash(&tip));
+            previous_block_hash.set_state(tip);
+            repaired = true;
+        }
+        drop(previous_block_hash);
+
+        if let Some(block) = self.block(&tip) {
+            let chain = self.chain.lock().unwrap();
+            self.update_difficulty(difficulty_at(&chain, chain_height,
+                block.header.difficulty));
+        }
+
+        if repaired {
+            match self.reindex_utxo_set() {
+                Ok(utxo_set) =>
+                    warn!("[REPAIRED UTXO SET][{}]", utxo_set.len()),
+                Err(err) => error!("[UTXO SET NOT REPAIRED][{:?}]", err)
+            }
+        }
+    }
+
+    fn update_difficulty(&self, difficulty: u32) {
+        let mut current = self.difficulty.lock().unwrap();
+        if **current != difficulty {
+            info!("[RETARGETED][{}][{}]", **current, difficulty);
+            current.set_state(difficulty);
+            self.metrics.set_difficulty(difficulty);
         }
     }
-}
 
+    fn is_txindex_current(&self) -> bool {
+        let tip = match self.blocks_rev().next() {
+            Some(Ok(tip)) => tip,
+            _ => return true
+        };
+
+        match &self.txindex {
+            Some(txindex) => {
+                let txindex = txindex.lock().unwrap();
+                tip.transactions()
+                    .iter()
+                    .all(|tx| txindex.contains_key(&tx.calculate_id()))
+            }
+            None => true
+        }
+    }
+
+    fn rebuild_txindex(&self) {
+        let txindex = match &self.txindex {
+            Some(txindex) => txindex,
+            None => return
+        };
+
+        let mut rebuilt = TxIndex::new();
+        for block in self.blocks().map_while(Result::ok) {
+            let block_hash = block.hash();
+            for (i, tx) in block.transactions().iter().enumerate() {
+                rebuilt.insert(tx.calculate_id(), (block_hash, i as u32));
+            }
+        }
+
+        info!("[REBUILT TXINDEX][{}]", rebuilt.len());
+        txindex.lock().unwrap().set_state(rebuilt);
+    }
+
+    pub fn block_offset(&self, hash: &Sha256Hash) -> Option<u64> {
+        self.block_index.lock().unwrap().get(hash).copied()
+    }
+
+    pub fn block_height_of(&self, hash: &Sha256Hash) -> Option<u32> {
+        let offset = self.block_offset(hash)?;
+        self.block_offsets
+            .lock()
+            .unwrap()
+            .binary_search(&offset)
+            .ok()
+            .map(|height| height as u32)
+    }
+
+    // Main chain blocks at heights from..to, read in one pass from the
+    // offset of the first. Pruned blocks come back as headers only.
+    fn main_chain_blocks(&self, from: u32, to: u32) -> Vec<Block> {
+        let offsets = self.block_offsets.lock().unwrap();
+        let offset = match offsets.get(from as usize) {
+            Some(offset) => *offset,
+            None => return Vec::new()
+        };
+        drop(offsets);
+        let chain = self.chain.lock().unwrap();
+
+        let mut reader = BufReader::new(chain.try_clone().unwrap());
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        (from..to)
+            .map_while(|_| Block::from_file(&mut reader))
+            .collect()
+    }
+
+    // The difficulty a child of `parent` has to carry, when the parent is on
+    // the main chain or on a known side branch.
+    pub fn child_difficulty(&self, parent: &Sha256Hash) -> Option<u32> {
+        let mut branch = Vec::new();
+        let mut hash = *parent;
+        let fork_point = {
+            let side_blocks = self.side_blocks.lock().unwrap();
+            loop {
+                if let Some(height) = self.block_height_of(&hash) {
+                    break height + 1;
+                }
+
+                let block = side_blocks.get(&hash)?;
+                branch.push(block.header.clone());
+                hash = block.header.previous_block;
+            }
+        };
+        branch.reverse();
+
+        let height = fork_point + branch.len() as u32;
+        let lookback = match is_retarget_height(height) {
+            true => RETARGET_INTERVAL,
+            false => 1
+        };
+        let ancestors = self
+            .main_chain_blocks(fork_point.saturating_sub(lookback), fork_point)
+            .into_iter()
+            .map(|block| block.header)
+            .chain(branch)
+            .collect::<Vec<_>>();
+        let timestamps = ancestors
+            .iter()
+            .map(|header| header.time_stamp)
+            .collect::<Vec<_>>();
+        let parent_difficulty = ancestors.last()?.difficulty;
+
+        Some(expected_difficulty(height, parent_difficulty, &timestamps))
+    }
+
+    pub fn block(&self, hash: &Sha256Hash) -> Option<Block> {
+        let offset = self.block_offset(hash)?;
+        let chain = self.chain.lock().unwrap();
+
+        let mut reader = BufReader::new(chain.try_clone().unwrap());
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        Block::from_file(&mut reader).filter(|block| !block.is_pruned())
+    }
+
+    pub fn accept_block(&self, block: Block)
+            -> Result<BlockAcceptance, BlockValidityError> {
+
+        let hash = block.hash();
+        let res = self.connect_block(block);
+        match &res {
+            Err(err) => self.metrics.record_rejected_block(err),
+            Ok(BlockAcceptance::Orphaned | BlockAcceptance::AlreadyKnown) => {}
+            Ok(_) => self.connect_orphans(hash)
+        }
+
+        res
+    }
+
+    fn connect_orphans(&self, parent: Sha256Hash) {
+        let mut parents = vec![parent];
+        while let Some(parent) = parents.pop() {
+            let children = self.orphans.lock().unwrap().take_children(&parent);
+            for child in children {
+                let hash = child.hash();
+                match self.connect_block(child) {
+                    Ok(BlockAcceptance::Orphaned
+                        | BlockAcceptance::AlreadyKnown) => {}
+                    Ok(_) => {
+                        info!("[CONNECTED ORPHAN][{}]", short_hash(&hash));
+                        parents.push(hash);
+                    }
+                    Err(err) => {
+                        warn!("[REJECTED ORPHAN][{}][{:?}]", short_hash(&hash),
+                            err);
+                        self.metrics.record_rejected_block(&err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn connect_block(&self, block: Block)
+            -> Result<BlockAcceptance, BlockValidityError> {
+
+        let hash = block.hash();
+        if self.block_offset(&hash).is_some()
+                || self.side_blocks.lock().unwrap().get(&hash).is_some()
+                || self.orphans.lock().unwrap().get(&hash).is_some() {
+            return Ok(BlockAcceptance::AlreadyKnown);
+        }
+
+        let tip = **self.previous_block_hash.lock().unwrap();
+        if block.header.previous_block == tip {
+            self.append_block(&block)?;
+            return Ok(BlockAcceptance::Connected);
+        }
+
+        // Walk back through the side blocks until the branch meets the main
+        // chain. Every side block already passed the checks below.
+        let side_blocks = self.side_blocks.lock().unwrap();
+        let mut branch = vec![block];
+        let fork_point = loop {
+            let parent = branch.last().unwrap().header.previous_block;
+            if parent == [0u8; 32] {
+                return Err(BlockValidityError::WrongGenesis);
+            }
+
+            if let Some(height) = self.block_height_of(&parent) {
+                break height + 1;
+            }
+
+            match side_blocks.get(&parent) {
+                Some(side_block) => branch.push(side_block.clone()),
+                None if branch.len() == 1 => {
+                    // The parent is unknown, so the header's own difficulty
+                    // is all that can be checked before holding on to it.
+                    let block = branch.pop().unwrap();
+                    if !block.header.is_valid_pow(block.header.difficulty) {
+                        return Err(BlockValidityError::InvalidHash);
+                    }
+                    debug!("[ORPHAN BLOCK][{}][{}]", short_hash(&block.hash()),
+                        short_hash(&parent));
+                    self.orphans.lock().unwrap().add(block);
+                    return Ok(BlockAcceptance::Orphaned);
+                }
+                None => return Err(BlockValidityError::UnknownParent)
+            }
+        };
+        drop(side_blocks);
+        branch.reverse();
+
+        let chain_height = **self.block_height.lock().unwrap();
+        if chain_height.saturating_sub(fork_point) > self.max_reorg_depth {
+            return Err(BlockValidityError::ReorgTooDeep);
+        }
+
+        // The new block has to carry the difficulty its own ancestors call
+        // for, so a side branch costs as much work as the main chain.
+        let block = branch.last().unwrap();
+        let difficulty = self.child_difficulty(&block.header.previous_block)
+            .ok_or(BlockValidityError::UnknownParent)?;
+        if block.header.difficulty != difficulty {
+            return Err(BlockValidityError::WrongDifficulty);
+        }
+        if !block.header.is_valid_pow(difficulty) {
+            return Err(BlockValidityError::InvalidHash);
+        }
+        self.side_blocks.lock().unwrap().add(block.clone());
+
+        let branch_difficulties = branch
+            .iter()
+            .map(|block| block.header.difficulty)
+            .collect::<Vec<_>>();
+        let main_chain_difficulties = self
+            .main_chain_blocks(fork_point, chain_height)
+            .iter()
+            .map(|block| block.header.difficulty)
+            .collect::<Vec<_>>();
+
+        if chain_work(&branch_difficulties)
+                <= chain_work(&main_chain_difficulties) {
+            info!("[SIDE BRANCH][{}][{}]", fork_point,
+                short_hash(&branch.last().unwrap().hash()));
+            return Ok(BlockAcceptance::SideBranch);
+        }
+
+        self.reorg_to(fork_point, &branch).map_err(|err| match err {
+            ReorgError::TooDeep(_) => BlockValidityError::ReorgTooDeep,
+            ReorgError::InvalidBlock(err) => err
+        })?;
+        Ok(BlockAcceptance::Reorganized(fork_point))
+    }
+
+    pub fn reorg_to(&self, fork_point: u32, new_blocks: &[Block])
+            -> Result<(), ReorgError> {
+
+        let mut chain = self.chain.lock().unwrap();
+        let mut utxo_set = self.utxo_set.lock().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut block_height = self.block_height.lock().unwrap();
+        let mut previous_block_hash = self.previous_block_hash.lock().unwrap();
+        let mut total_work = self.chain_work.lock().unwrap();
+        let reward = **self.reward.lock().unwrap();
+        let pruned_height = **self.pruned_height.lock().unwrap();
+
+        let main_chain = read_chain(&chain);
+        if fork_point == 0 {
+            return Err(BlockValidityError::WrongGenesis.into());
+        }
+        if fork_point < pruned_height {
+            return Err(BlockValidityError::PrunedHistory.into());
+        }
+        if fork_point as usize > main_chain.len() {
+            return Err(BlockValidityError::UnknownParent.into());
+        }
+        let depth = main_chain.len() as u32 - fork_point;
+        if depth > self.max_reorg_depth {
+            warn!("[REORG TOO DEEP][{}][{}]", fork_point, depth);
+            return Err(ReorgError::TooDeep(depth));
+        }
+        let disconnects_checkpoint = self.checkpoints
+            .keys()
+            .any(|height| (fork_point..**block_height).contains(height));
+        if disconnects_checkpoint {
+            return Err(BlockValidityError::CheckpointMismatch.into());
+        }
+        let (kept, disconnected) = main_chain.split_at(fork_point as usize);
+
+        let mut working_set = (**utxo_set).clone();
+        let mut pending_utxos = HashSet::new();
+        for block in disconnected.iter().rev() {
+            block.rewind(&mut working_set, &mut pending_utxos);
+        }
+        for (height, block) in kept.iter().enumerate().rev() {
+            if pending_utxos.is_empty() {
+                break;
+            }
+            block.add_pending_utxos_to_utxo_set(&mut working_set,
+                &mut pending_utxos, height as u32);
+        }
+        if !pending_utxos.is_empty() {
+            return Err(BlockValidityError::PrunedHistory.into());
+        }
+
+        let mut parent = kept
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or([0u8; 32]);
+        let mut parent_difficulty = kept
+            .last()
+            .map(|block| block.header.difficulty)
+            .unwrap_or(0);
+        let mut timestamps = kept
+            .iter()
+            .map(|block| block.header.time_stamp)
+            .collect::<Vec<_>>();
+        for (i, block) in new_blocks.iter().enumerate() {
+            let height = fork_point + i as u32;
+            let difficulty = expected_difficulty(height, parent_difficulty,
+                &timestamps);
+            let res = self.check_checkpoint(block, height).and_then(|_|
+                block.validate_in_context(&parent, &timestamps, difficulty,
+                    reward, &working_set, height, self.chain_id,
+                    self.verify_signatures_at(height)));
+            if let Err(err) = res {
+                warn!("[REORG FAILED][{}][{:?}]", fork_point, err);
+                return Err(err.into());
+            }
+
+            block.apply(&mut working_set, height);
+            timestamps.push(block.header.time_stamp);
+            parent = block.hash();
+            parent_difficulty = block.header.difficulty;
+        }
+
+        let fork_offset = kept
+            .iter()
+            .map(|block| 8 + bincode::serialized_size(block).unwrap())
+            .sum::<u64>();
+        chain.set_len(fork_offset).unwrap();
+        let mut block_index = self.block_index.lock().unwrap();
+        let mut block_offsets = self.block_offsets.lock().unwrap();
+        for block in disconnected {
+            block_index.remove(&block.hash());
+            self.unindex_transactions(block);
+        }
+        block_offsets.truncate(fork_point as usize);
+        for (i, block) in new_blocks.iter().enumerate() {
+            let offset = chain.seek(SeekFrom::End(0)).unwrap();
+            block.write_to_file(&mut chain);
+            block_index.insert(block.hash(), offset);
+            block_offsets.push(offset);
+            self.index_transactions(block, fork_point + i as u32);
+        }
+        drop(block_offsets);
+        drop(block_index);
+
+        utxo_set.set_state(working_set);
+        self.publish_utxo_snapshot(&utxo_set);
+
+        let new_height = fork_point + new_blocks.len() as u32;
+        for block in new_blocks {
+            block.update_mempool(&mut mempool);
+        }
+        let disconnected_txs = disconnected
+            .iter()
+            .flat_map(|block| block.transactions())
+            .filter(|tx| !tx.inputs().is_empty());
+        for tx in disconnected_txs {
+            let _ = mempool.add(tx.clone(), &utxo_set, new_height,
+                self.chain_id);
+        }
+        mempool.update();
+        self.metrics.set_mempool_size(mempool.len());
+        self.update_difficulty(expected_difficulty(new_height,
+            parent_difficulty, &timestamps));
+
+        let work_of = |blocks: &[Block]| chain_work(&blocks
+            .iter()
+            .map(|block| block.header.difficulty)
+            .collect::<Vec<_>>());
+        let new_work = (**total_work)
+            .saturating_sub(work_of(disconnected))
+            .saturating_add(work_of(new_blocks));
+
+        block_height.set_state(new_height);
+        previous_block_hash.set_state(parent);
+        total_work.set_state(new_work);
+
+        let mut side_blocks = self.side_blocks.lock().unwrap();
+        for block in disconnected {
+            side_blocks.add(block.clone());
+        }
+        for block in new_blocks {
+            side_blocks.remove(&block.hash());
+        }
+
+        let new_tip = new_blocks
+            .last()
+            .map(|block| short_hash(&block.hash()))
+            .unwrap_or_default();
+        info!("[REORG][{}][{}][{}]", fork_point, new_height, new_tip);
+        Ok(())
+    }
+
+    // Drops the bodies of all blocks buried under more than keep_last blocks,
+    // keeping their headers so the chain file framing and PoW history stay
+    // intact. A pruned node can no longer serve GetBlock for those heights,
+    // reorganize below them, or verify and export its full chain.
+    pub fn prune(&self, keep_last: u32) -> io::Result<u32> {
+        let mut chain = self.chain.lock().unwrap();
+        let block_height = self.block_height.lock().unwrap();
+        let mut pruned_height = self.pruned_height.lock().unwrap();
+
+        let prune_to = block_height.saturating_sub(keep_last);
+        if prune_to <= **pruned_height {
+            return Ok(**pruned_height);
+        }
+
+        let blocks = read_chain(&chain);
+        let chain_path = self.data_dir.join("chain");
+        let pruned_path = self.data_dir.join("chain.tmp");
+        let mut pruned_chain = File::create(&pruned_path)?;
+        let mut block_index = HashMap::new();
+        let mut block_offsets = Vec::new();
+        for (height, block) in blocks.iter().enumerate() {
+            let offset = pruned_chain.stream_position()?;
+            if (height as u32) < prune_to {
+                block.to_pruned().write_to_file(&mut pruned_chain);
+            } else {
+                block.write_to_file(&mut pruned_chain);
+            }
+            block_index.insert(block.hash(), offset);
+            block_offsets.push(offset);
+        }
+        pruned_chain.sync_all()?;
+        drop(pruned_chain);
+
+        fs::rename(&pruned_path, &chain_path)?;
+        *chain = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&chain_path)?;
+        *self.block_index.lock().unwrap() = block_index;
+        *self.block_offsets.lock().unwrap() = block_offsets;
+        pruned_height.set_state(prune_to);
+
+        info!("[PRUNED][{}]", prune_to);
+        Ok(prune_to)
+    }
+
+    pub fn total_supply(&self) -> u64 {
+        self.utxo_set
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.output.amount() as u64)
+            .sum()
+    }
+
+    pub fn verify_chain(&self, check_supply: bool) -> Result<u32, VerifyError> {
+        let mut blocks = Vec::new();
+        for block in self.blocks() {
+            match block {
+                Ok(block) => blocks.push(block),
+                Err(err) => {
+                    error!("[UNREADABLE BLOCK][{}][{:?}]", blocks.len(), err);
+                    return Err(VerifyError::InvalidBlock(blocks.len() as u32,
+                        BlockValidityError::Unreadable));
+                }
+            }
+        }
+        let reward = **self.reward.lock().unwrap();
+
+        replay_chain(&blocks, &self.genesis_hash, reward, self.chain_id,
+                &self.checkpoints)
+            .map_err(|(height, err)| VerifyError::InvalidBlock(height, err))?;
+
+        if check_supply {
+            let expected = expected_supply(&blocks, reward);
+            let actual = self.total_supply();
+            if expected != actual {
+                return Err(VerifyError::SupplyMismatch(expected, actual));
+            }
+        }
+
+        Ok(blocks.len() as u32)
+    }
+
+    pub fn reindex_utxo_set(&self) -> Result<UTXOSet, ReindexError> {
+        let chain = self.chain.lock().unwrap();
+        let mut utxo_set = self.utxo_set.lock().unwrap();
+        let reward = **self.reward.lock().unwrap();
+
+        if **self.pruned_height.lock().unwrap() > 0 {
+            return Err(ReindexError::PrunedHistory);
+        }
+
+        let blocks = BlockIter::new(chain.try_clone().unwrap())
+            .map_err(|err| ReindexError::Read(BlockReadError::Io(err)))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ReindexError::Read)?;
+        let (rebuilt, _) = replay_chain(&blocks, &self.genesis_hash, reward,
+                self.chain_id, &self.checkpoints)
+            .map_err(|(height, err)| ReindexError::InvalidBlock(height, err))?;
+
+        utxo_set.set_state(rebuilt.clone());
+        self.publish_utxo_snapshot(&utxo_set);
+
+        info!("[REINDEXED UTXO SET][{}][{}]", blocks.len(), rebuilt.len());
+        Ok(rebuilt)
+    }
+
+    pub fn export_chain(&self, path: &str) -> io::Result<u32> {
+        let block_count = **self.block_height.lock().unwrap();
+
+        // Pruned blocks are stored without transactions, so an export of
+        // a pruned chain could not be replayed by import_chain.
+        let pruned_height = **self.pruned_height.lock().unwrap();
+        if pruned_height > 0 {
+            return Err(io::Error::new(ErrorKind::Unsupported,
+                format!("chain is pruned below height {}", pruned_height)));
+        }
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(CHAIN_EXPORT_MAGIC)?;
+        file.write_all(&CHAIN_EXPORT_VERSION.to_le_bytes())?;
+        file.write_all(&block_count.to_le_bytes())?;
+        for block in self.blocks().take(block_count as usize) {
+            let block = block.map_err(|err| io::Error::new(
+                ErrorKind::InvalidData, format!("{:?}", err)))?;
+            let serialized_block = bincode::serialize(&block).unwrap();
+            file.write_all(&(serialized_block.len() as u32).to_le_bytes())?;
+            file.write_all(&serialized_block)?;
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+
+        info!("[EXPORTED CHAIN][{}][{}]", path, block_count);
+        Ok(block_count)
+    }
+
+    pub fn import_chain(&self, path: &str) -> Result<u32, ImportError> {
+        let blocks = read_export(path)?;
+
+        let mut chain = self.chain.lock().unwrap();
+        let mut utxo_set = self.utxo_set.lock().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut block_height = self.block_height.lock().unwrap();
+        let mut previous_block_hash = self.previous_block_hash.lock().unwrap();
+        let mut total_work = self.chain_work.lock().unwrap();
+        let reward = **self.reward.lock().unwrap();
+
+        let (working_set, parent) = replay_chain(&blocks, &self.genesis_hash,
+                reward, self.chain_id, &self.checkpoints)
+            .map_err(|(height, err)| ImportError::InvalidBlock(height, err))?;
+
+        // The chain is rebuilt next to the live one and renamed over it, so
+        // a failed write leaves the existing chain as it was.
+        let chain_path = self.data_dir.join("chain");
+        let imported_path = self.data_dir.join("chain.tmp");
+        let mut imported_chain = File::create(&imported_path)
+            .map_err(ImportError::Io)?;
+        let mut new_block_index = HashMap::new();
+        let mut new_block_offsets = Vec::new();
+        for block in &blocks {
+            let offset = imported_chain.stream_position()
+                .map_err(ImportError::Io)?;
+            block.write_to_file(&mut imported_chain);
+            new_block_index.insert(block.hash(), offset);
+            new_block_offsets.push(offset);
+        }
+        imported_chain.sync_all().map_err(ImportError::Io)?;
+        drop(imported_chain);
+
+        fs::rename(&imported_path, &chain_path).map_err(ImportError::Io)?;
+        *chain = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&chain_path)
+            .map_err(ImportError::Io)?;
+
+        *self.block_index.lock().unwrap() = new_block_index;
+        *self.block_offsets.lock().unwrap() = new_block_offsets;
+        self.tx_heights.lock().unwrap().clear();
+        if let Some(txindex) = &self.txindex {
+            txindex.lock().unwrap().set_state(TxIndex::new());
+        }
+        for (height, block) in blocks.iter().enumerate() {
+            self.index_transactions(block, height as u32);
+        }
+        *self.side_blocks.lock().unwrap() = OrphanPool::new(MAX_SIDE_BLOCKS);
+        self.pruned_height.lock().unwrap().set_state(0);
+
+        let new_height = blocks.len() as u32;
+        let mut new_mempool = Mempool::new(MEMPOOL_MAX_COUNT, MEMPOOL_MAX_AGE);
+        for tx in dependency_order(mempool.iter()) {
+            let _ = new_mempool.add(tx.clone(), &working_set, new_height,
+                self.chain_id);
+        }
+        mempool.set_state(new_mempool);
+        self.metrics.set_mempool_size(mempool.len());
+        utxo_set.set_state(working_set);
+        self.publish_utxo_snapshot(&utxo_set);
+
+        block_height.set_state(new_height);
+        previous_block_hash.set_state(parent);
+        total_work.set_state(chain_work(&blocks
+            .iter()
+            .map(|block| block.header.difficulty)
+            .collect::<Vec<_>>()));
+        self.update_difficulty(next_difficulty(&blocks));
+
+        info!("[IMPORTED CHAIN][{}][{}]", path, new_height);
+        Ok(new_height)
+    }
+}
+
+fn replay_chain(blocks: &[Block], genesis_hash: &Sha256Hash, reward: u32,
+        chain_id: u32, checkpoints: &HashMap<u32, Sha256Hash>)
+        -> Result<(UTXOSet, Sha256Hash), (u32, BlockValidityError)> {
+
+    let genesis = match blocks.first() {
+        Some(block) if block.hash() == *genesis_hash => block,
+        _ => return Err((0, BlockValidityError::WrongGenesis))
+    };
+    genesis.is_valid_genesis().map_err(|err| (0, err))?;
+
+    let mut utxo_set = UTXOSet::new();
+    genesis.apply(&mut utxo_set, 0);
+    let mut parent = genesis.hash();
+    for (height, block) in blocks.iter().enumerate().skip(1) {
+        let previous_timestamps = blocks[..height]
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|block| block.header.time_stamp)
+            .collect::<Vec<_>>();
+        let difficulty = next_difficulty(&blocks[..height]);
+
+        let height = height as u32;
+        if checkpoints.get(&height).is_some_and(|hash| *hash != block.hash()) {
+            return Err((height, BlockValidityError::CheckpointMismatch));
+        }
+        block.is_valid_in_context(&parent, &previous_timestamps, difficulty,
+                reward, &utxo_set, height, chain_id)
+            .map_err(|err| (height, err))?;
+
+        block.apply(&mut utxo_set, height);
+        parent = block.hash();
+    }
+
+    Ok((utxo_set, parent))
+}
+
+// Genesis allocations plus the subsidy of every later block, less whatever
+// each miner left unclaimed. Reward that no coinbase claimed is burned.
+fn expected_supply(blocks: &[Block], reward: u32) -> u64 {
+    let mut utxo_set = UTXOSet::new();
+    let mut supply = 0u64;
+    for (height, block) in blocks.iter().enumerate() {
+        let height = height as u32;
+        let (coinbase, txs) = match block.transactions().split_first() {
+            Some(split) => split,
+            None => continue
+        };
+        let claimed = output_total(coinbase);
+
+        let spent = block.apply(&mut utxo_set, height)
+            .iter()
+            .map(|(_, entry)| entry.output.amount() as u64)
+            .sum::<u64>();
+        if height == 0 {
+            supply += claimed;
+            continue;
+        }
+
+        let fees = spent.saturating_sub(txs.iter().map(output_total).sum());
+        let subsidy = block_subsidy(height, reward, HALVING_INTERVAL) as u64;
+        let burned = (subsidy + fees).saturating_sub(claimed);
+        supply = (supply + subsidy).saturating_sub(burned);
+    }
+
+    supply
+}
+
+fn output_total(tx: &Transaction) -> u64 {
+    tx.outputs()
+        .iter()
+        .map(|output| output.amount() as u64)
+        .sum()
+}
+
+fn read_export(path: &str) -> Result<Vec<Block>, ImportError> {
+    let mut file = BufReader::new(File::open(path).map_err(ImportError::Io)?);
+
+    let mut magic = vec![0u8; CHAIN_EXPORT_MAGIC.len()];
+    file.read_exact(&mut magic).map_err(|_| ImportError::InvalidFormat)?;
+    if magic != CHAIN_EXPORT_MAGIC {
+        return Err(ImportError::InvalidFormat);
+    }
+
+    let mut word = [0u8; 4];
+    file.read_exact(&mut word).map_err(|_| ImportError::InvalidFormat)?;
+    let version = u32::from_le_bytes(word);
+    if version != CHAIN_EXPORT_VERSION {
+        return Err(ImportError::UnsupportedVersion(version));
+    }
+
+    file.read_exact(&mut word).map_err(|_| ImportError::InvalidFormat)?;
+    let count = u32::from_le_bytes(word);
+
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        file.read_exact(&mut word).map_err(|_| ImportError::InvalidFormat)?;
+        let len = u32::from_le_bytes(word) as usize;
+        if len > MAX_BLOCK_SIZE {
+            return Err(ImportError::InvalidFormat);
+        }
+
+        let mut serialized_block = vec![0u8; len];
+        file.read_exact(&mut serialized_block)
+            .map_err(|_| ImportError::InvalidFormat)?;
+        let block = bincode::deserialize(&serialized_block)
+            .map_err(|_| ImportError::InvalidFormat)?;
+        blocks.push(block);
+    }
+
+    if file.read(&mut word).map_err(ImportError::Io)? != 0 {
+        return Err(ImportError::InvalidFormat);
+    }
+
+    Ok(blocks)
+}
+
+// Difficulty required of the block that would follow `blocks`.
+fn next_difficulty(blocks: &[Block]) -> u32 {
+    let parent_difficulty = match blocks.last() {
+        Some(block) => block.header.difficulty,
+        None => return 0
+    };
+    let start = blocks.len().saturating_sub(RETARGET_INTERVAL as usize);
+    let timestamps = blocks[start..]
+        .iter()
+        .map(|block| block.header.time_stamp)
+        .collect::<Vec<_>>();
+
+    expected_difficulty(blocks.len() as u32, parent_difficulty, &timestamps)
+}
+
+// Difficulty required of the block at `height` on top of the chain file.
+// Only retarget boundaries need to read back through the chain.
+fn difficulty_at(chain: &File, height: u32, parent_difficulty: u32) -> u32 {
+    if !is_retarget_height(height) {
+        return parent_difficulty;
+    }
+
+    let mut reader = BufReader::new(chain.try_clone().unwrap());
+    reader.seek(SeekFrom::End(0)).unwrap();
+    retarget_difficulty(&mut reader, parent_difficulty, TARGET_BLOCK_TIME,
+        RETARGET_INTERVAL)
+}
+
+fn recent_timestamps(chain: &File, count: usize) -> Vec<SystemTime> {
+    let mut timestamps = BlockRevIter::new(chain.try_clone().unwrap())
+        .unwrap()
+        .map_while(Result::ok)
+        .take(count)
+        .map(|block| block.header.time_stamp)
+        .collect::<Vec<_>>();
+
+    timestamps.reverse();
+    timestamps
+}
+
+fn index_chain(chain: &File) -> ChainIndex {
+
+    let mut blocks = BlockIter::new(chain.try_clone().unwrap()).unwrap();
+
+    let mut block_index = HashMap::new();
+    let mut block_offsets = Vec::new();
+    let mut tx_heights = HashMap::new();
+    let read_error = loop {
+        let offset = blocks.offset();
+        let block = match blocks.next() {
+            Some(Ok(block)) => block,
+            Some(Err(err)) => break Some(err),
+            None => break None
+        };
+
+        let height = block_offsets.len() as u32;
+        for tx in block.transactions() {
+            tx_heights.insert(tx.calculate_id(), height);
+        }
+        block_index.insert(block.hash(), offset);
+        block_offsets.push(offset);
+    };
+
+    (block_index, block_offsets, tx_heights, read_error)
+}
+
+// A write torn by a crash leaves an unframed tail. If the file still ends in
+// a whole block, the frame that failed to read is damage in the middle of
+// the chain instead, and cutting there would drop every block after it.
+fn is_torn_tail(chain: &File) -> bool {
+    let last_block = chain.try_clone()
+        .and_then(BlockRevIter::new)
+        .map(|mut blocks| blocks.next());
+    !matches!(last_block, Ok(Some(Ok(_))))
+}
+
+fn read_chain(chain: &File) -> Vec<Block> {
+    BlockIter::new(chain.try_clone().unwrap())
+        .unwrap()
+        .map_while(Result::ok)
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testutil::{make_keypair, TempDir};
+
+    const DIFFICULTY: u32 = 4;
+
+    fn open_state(dir: &TempDir) -> GlobalState {
+        let genesis = GenesisConfig::new().set_difficulty(DIFFICULTY);
+        GlobalState::open(dir.path(), genesis, false, false).unwrap()
+    }
+
+    fn mine_blocks(state: &GlobalState, count: u32) {
+        let (_, miner) = make_keypair();
+        for _ in 0..count {
+            state.mine_next_block(&miner).unwrap();
+        }
+    }
+
+    #[test]
+    fn difficulty_retargets_on_interval_boundary() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+
+        mine_blocks(&state, RETARGET_INTERVAL - 2);
+        assert_eq!(**state.difficulty.lock().unwrap(), DIFFICULTY);
+
+        // The genesis timestamp lies far in the past, so the interval looks
+        // slow and the difficulty drops by the maximum step.
+        mine_blocks(&state, 1);
+        assert_eq!(**state.difficulty.lock().unwrap(), DIFFICULTY - 2);
+
+        let (_, miner) = make_keypair();
+        let mut block = Block::new();
+        block.add_coinbase(&miner, **state.reward.lock().unwrap(),
+            &UTXOSet::new(), RETARGET_INTERVAL, state.chain_id);
+        block.set_previous_block(&state.previous_block_hash.lock().unwrap());
+        block.mine(DIFFICULTY);
+        assert!(matches!(state.validate_block(&block),
+            Err(BlockValidityError::WrongDifficulty)));
+
+        mine_blocks(&state, 1);
+        assert!(state.verify_chain(false).is_ok());
+    }
+
+    #[test]
+    fn reverse_iterator_mirrors_the_forward_one() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 5);
+
+        let forward = state.blocks()
+            .map(|block| block.unwrap().hash())
+            .collect::<Vec<_>>();
+        let mut reverse = state.blocks_rev()
+            .map(|block| block.unwrap().hash())
+            .collect::<Vec<_>>();
+        reverse.reverse();
+
+        assert_eq!(forward.len(), 6);
+        assert_eq!(forward, reverse);
+    }
+
+    #[test]
+    fn headers_are_read_from_the_requested_height() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 4);
+        let hashes = state.blocks()
+            .map(|block| block.unwrap().hash())
+            .collect::<Vec<_>>();
+
+        let headers = state.headers(2, 2);
+        assert_eq!(headers.iter().map(|header| header.hash())
+            .collect::<Vec<_>>(), hashes[2..4]);
+        assert_eq!(state.headers(3, 10).len(), 2);
+        assert!(state.headers(5, 10).is_empty());
+        assert!(state.headers(u32::MAX, u32::MAX).is_empty());
+    }
+
+    fn block_on(state: &GlobalState, parent: &Sha256Hash, height: u32,
+            difficulty: u32) -> Block {
+
+        let (_, miner) = make_keypair();
+        let mut block = Block::new();
+        block.add_coinbase(&miner, **state.reward.lock().unwrap(),
+            &UTXOSet::new(), height, state.chain_id);
+        block.set_previous_block(parent);
+        block.mine(difficulty);
+        block
+    }
+
+    #[test]
+    fn longer_side_branch_reorganizes_the_chain() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        let (_, miner) = make_keypair();
+        let fork = state.mine_next_block(&miner).unwrap().hash();
+        mine_blocks(&state, 2);
+
+        let b2 = block_on(&state, &fork, 2, DIFFICULTY);
+        let b3 = block_on(&state, &b2.hash(), 3, DIFFICULTY);
+        let b4 = block_on(&state, &b3.hash(), 4, DIFFICULTY);
+        assert!(matches!(state.accept_block(b2),
+            Ok(BlockAcceptance::SideBranch)));
+        assert!(matches!(state.accept_block(b3),
+            Ok(BlockAcceptance::SideBranch)));
+        assert!(matches!(state.accept_block(b4.clone()),
+            Ok(BlockAcceptance::Reorganized(2))));
+
+        assert_eq!(**state.previous_block_hash.lock().unwrap(), b4.hash());
+        assert_eq!(state.block_height_of(&b4.hash()), Some(4));
+    }
+
+    #[test]
+    fn orphan_without_valid_work_is_rejected() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        let mut orphan = block_on(&state, &[7u8; 32], 5, DIFFICULTY);
+        orphan.header.difficulty = 255;
+        let hash = orphan.hash();
+
+        assert!(matches!(state.accept_block(orphan),
+            Err(BlockValidityError::InvalidHash)));
+        assert!(state.orphans.lock().unwrap().get(&hash).is_none());
+    }
+
+    #[test]
+    fn known_blocks_are_not_processed_again() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        let (_, miner) = make_keypair();
+        let fork = state.mine_next_block(&miner).unwrap().hash();
+        let tip = state.mine_next_block(&miner).unwrap();
+
+        let side = block_on(&state, &fork, 2, DIFFICULTY);
+        let orphan = block_on(&state, &[7u8; 32], 5, DIFFICULTY);
+        assert!(matches!(state.accept_block(side.clone()),
+            Ok(BlockAcceptance::SideBranch)));
+        assert!(matches!(state.accept_block(orphan.clone()),
+            Ok(BlockAcceptance::Orphaned)));
+
+        for block in [tip, side, orphan] {
+            assert!(matches!(state.accept_block(block),
+                Ok(BlockAcceptance::AlreadyKnown)));
+        }
+        assert_eq!(state.block_height_of(&fork), Some(1));
+    }
+
+    #[test]
+    fn side_block_must_meet_consensus_difficulty() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        let (_, miner) = make_keypair();
+        let fork = state.mine_next_block(&miner).unwrap().hash();
+        mine_blocks(&state, 1);
+
+        let easy = block_on(&state, &fork, 2, DIFFICULTY - 1);
+        let hash = easy.hash();
+        assert!(matches!(state.accept_block(easy),
+            Err(BlockValidityError::WrongDifficulty)));
+        assert!(state.side_blocks.lock().unwrap().get(&hash).is_none());
+    }
+
+    #[test]
+    fn reorg_deeper_than_the_limit_is_refused() {
+        let dir = TempDir::new();
+        let state = open_state(&dir).set_max_reorg_depth(2);
+        let (_, miner) = make_keypair();
+        let deep_fork = state.mine_next_block(&miner).unwrap().hash();
+        mine_blocks(&state, 1);
+        let shallow_fork = state.mine_next_block(&miner).unwrap().hash();
+        let tip = state.mine_next_block(&miner).unwrap().hash();
+
+        // Five blocks from height 2 outweigh the three they would replace.
+        let mut parent = deep_fork;
+        let mut branch = Vec::new();
+        for height in 2..7 {
+            let block = block_on(&state, &parent, height, DIFFICULTY);
+            parent = block.hash();
+            branch.push(block);
+        }
+        assert!(matches!(state.accept_block(branch[0].clone()),
+            Err(BlockValidityError::ReorgTooDeep)));
+        assert!(matches!(state.reorg_to(2, &branch),
+            Err(ReorgError::TooDeep(3))));
+        assert_eq!(**state.previous_block_hash.lock().unwrap(), tip);
+
+        let b4 = block_on(&state, &shallow_fork, 4, DIFFICULTY);
+        let b5 = block_on(&state, &b4.hash(), 5, DIFFICULTY);
+        assert!(matches!(state.accept_block(b4),
+            Ok(BlockAcceptance::SideBranch)));
+        assert!(matches!(state.accept_block(b5),
+            Ok(BlockAcceptance::Reorganized(4))));
+    }
+
+    #[test]
+    fn stale_height_and_tip_are_repaired_from_the_chain_file() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 3);
+        let tip = **state.previous_block_hash.lock().unwrap();
+        let utxo_count = state.utxo_set.lock().unwrap().len();
+
+        state.block_height.lock().unwrap().set_state(99);
+        state.previous_block_hash.lock().unwrap().set_state([0u8; 32]);
+        state.utxo_set.lock().unwrap().set_state(UTXOSet::new());
+        drop(state);
+
+        let state = open_state(&dir);
+        assert_eq!(**state.block_height.lock().unwrap(), 4);
+        assert_eq!(**state.previous_block_hash.lock().unwrap(), tip);
+        assert_eq!(state.utxo_set.lock().unwrap().len(), utxo_count);
+    }
+
+    #[test]
+    fn partially_written_block_is_truncated() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 3);
+        let tip = **state.previous_block_hash.lock().unwrap();
+        drop(state);
+
+        let chain_path = dir.path().join("chain");
+        let framed_len = fs::metadata(&chain_path).unwrap().len();
+        let mut chain = OpenOptions::new()
+            .append(true)
+            .open(&chain_path)
+            .unwrap();
+        chain.write_all(&[0xff; 16]).unwrap();
+        drop(chain);
+
+        let state = open_state(&dir);
+        assert_eq!(fs::metadata(&chain_path).unwrap().len(), framed_len);
+        assert_eq!(**state.block_height.lock().unwrap(), 4);
+        assert_eq!(**state.previous_block_hash.lock().unwrap(), tip);
+
+        mine_blocks(&state, 1);
+        assert!(state.verify_chain(false).is_ok());
+    }
+
+    #[test]
+    fn corrupt_middle_block_is_left_in_place() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 3);
+        let offsets = state.block_offsets.lock().unwrap().clone();
+        drop(state);
+
+        // Break the trailing length of block 1, so its frame no longer
+        // matches while the blocks after it are still whole.
+        let chain_path = dir.path().join("chain");
+        let mut bytes = fs::read(&chain_path).unwrap();
+        bytes[offsets[2] as usize - 1] ^= 0xff;
+        fs::write(&chain_path, &bytes).unwrap();
+
+        let genesis = GenesisConfig::new().set_difficulty(DIFFICULTY);
+        assert!(matches!(GlobalState::open(dir.path(), genesis, false, false),
+            Err(StateError::CorruptChain(BlockReadError::Corrupt(_)))));
+        assert_eq!(fs::metadata(&chain_path).unwrap().len(),
+            bytes.len() as u64);
+    }
+
+    #[test]
+    fn unreadable_block_fails_verification() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 3);
+        let offsets = state.block_offsets.lock().unwrap().clone();
+
+        let chain_path = dir.path().join("chain");
+        let mut bytes = fs::read(&chain_path).unwrap();
+        bytes[offsets[3] as usize - 1] ^= 0xff;
+        fs::write(&chain_path, &bytes).unwrap();
+
+        assert!(matches!(state.verify_chain(false),
+            Err(VerifyError::InvalidBlock(2, BlockValidityError::Unreadable))));
+    }
+
+    #[test]
+    fn import_replaces_the_chain_through_a_renamed_file() {
+        let dir = TempDir::new();
+        let state = open_state(&dir);
+        mine_blocks(&state, 2);
+        let export_path = dir.path().join("export");
+        state.export_chain(export_path.to_str().unwrap()).unwrap();
+        let tip = **state.previous_block_hash.lock().unwrap();
+
+        mine_blocks(&state, 2);
+        assert_eq!(state.import_chain(export_path.to_str().unwrap()).unwrap(),
+            3);
+        assert_eq!(**state.previous_block_hash.lock().unwrap(), tip);
+        assert!(!dir.path().join("chain.tmp").exists());
+        assert!(state.verify_chain(true).is_ok());
+        drop(state);
+
+        let state = open_state(&dir);
+        assert_eq!(**state.block_height.lock().unwrap(), 3);
+        assert_eq!(state.block_height_of(&tip), Some(2));
+    }
+
+    fn truncate_to_half(path: &Path) {
+        let len = fs::metadata(path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(len / 2))
+            .unwrap();
+    }
+
+    #[test]
+    fn torn_state_file_recovers_from_the_temp_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("state");
+        let mut state = StateWithFile::new(&path, Vec::<u64>::new()).unwrap();
+        state.set_state(vec![1, 2, 3, 4]);
+        drop(state);
+
+        fs::copy(&path, dir.path().join("state.tmp")).unwrap();
+        truncate_to_half(&path);
+
+        let state = StateWithFile::new(&path, Vec::<u64>::new()).unwrap();
+        assert_eq!(*state, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn torn_temp_file_leaves_the_state_intact() {
+        let dir = TempDir::new();
+        let path = dir.path().join("state");
+        let mut state = StateWithFile::new(&path, Vec::<u64>::new()).unwrap();
+        state.set_state(vec![1, 2, 3, 4]);
+        drop(state);
+
+        let tmp_path = dir.path().join("state.tmp");
+        fs::write(&tmp_path, bincode::serialize(&vec![5u64; 8]).unwrap())
+            .unwrap();
+        truncate_to_half(&tmp_path);
+
+        let state = StateWithFile::new(&path, Vec::<u64>::new()).unwrap();
+        assert_eq!(*state, [1, 2, 3, 4]);
+    }
+}
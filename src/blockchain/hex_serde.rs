@@ -0,0 +1,81 @@
+use k256::ecdsa::{Signature, VerifyingKey};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+
+pub mod verifying_key {
+    use super::*;
+
+    pub fn serialize<S>(key: &VerifyingKey, serializer: S)
+            -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        if !serializer.is_human_readable() {
+            return key.serialize(serializer);
+        }
+
+        let bytes = key.to_encoded_point(true);
+        serializer.serialize_str(&hex::encode(bytes.as_bytes()))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D)
+            -> Result<VerifyingKey, D::Error>
+        where D: Deserializer<'de>
+    {
+        if !deserializer.is_human_readable() {
+            return VerifyingKey::deserialize(deserializer);
+        }
+
+        let bytes = hex::decode(String::deserialize(deserializer)?)
+            .map_err(D::Error::custom)?;
+        VerifyingKey::from_sec1_bytes(&bytes).map_err(D::Error::custom)
+    }
+}
+
+pub mod optional_verifying_key {
+    use super::*;
+
+    #[derive(Deserialize, Serialize)]
+    struct HexKey(#[serde(with = "verifying_key")] VerifyingKey);
+
+    pub fn serialize<S>(key: &Option<VerifyingKey>, serializer: S)
+            -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        key.map(HexKey).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D)
+            -> Result<Option<VerifyingKey>, D::Error>
+        where D: Deserializer<'de>
+    {
+        Ok(Option::<HexKey>::deserialize(deserializer)?.map(|key| key.0))
+    }
+}
+
+pub mod signature {
+    use super::*;
+
+    pub fn serialize<S>(signature: &Signature, serializer: S)
+            -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        if !serializer.is_human_readable() {
+            return signature.serialize(serializer);
+        }
+
+        serializer.serialize_str(&hex::encode(signature.to_bytes()))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D)
+            -> Result<Signature, D::Error>
+        where D: Deserializer<'de>
+    {
+        if !deserializer.is_human_readable() {
+            return Signature::deserialize(deserializer);
+        }
+
+        let bytes = hex::decode(String::deserialize(deserializer)?)
+            .map_err(D::Error::custom)?;
+        Signature::from_slice(&bytes).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,499 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, SystemTime}
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::transaction::{
+    Sha256Hash,
+    Transaction,
+    TransactionValidityError,
+    UTXOEntry,
+    UTXOSet
+};
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Mempool {
+    transactions: HashSet<Transaction>,
+    fees: HashMap<Sha256Hash, u32>,
+    spent_outpoints: HashMap<(Sha256Hash, u32), Sha256Hash>,
+    max_count: usize,
+    max_age: Duration
+}
+
+#[derive(Debug)]
+pub enum MempoolError {
+    AlreadyInMempool,
+    Conflict(Sha256Hash),
+    InvalidTransaction(TransactionValidityError),
+    Expired,
+    InsufficientFeeBump,
+    MempoolFull
+}
+
+impl Mempool {
+    pub fn new(max_count: usize, max_age: Duration) -> Self {
+        Mempool {
+            transactions: HashSet::new(),
+            fees: HashMap::new(),
+            spent_outpoints: HashMap::new(),
+            max_count,
+            max_age
+        }
+    }
+
+    pub fn add(&mut self, tx: Transaction, utxo_set: &UTXOSet, height: u32,
+            chain_id: u32) -> Result<(), MempoolError> {
+
+        if self.transactions.contains(&tx) {
+            return Err(MempoolError::AlreadyInMempool);
+        }
+
+        if let Some(conflict) = self.conflicts(&tx).into_iter().next() {
+            return Err(MempoolError::Conflict(conflict));
+        }
+
+        let fee = self.validate(&tx, utxo_set, height, chain_id)
+            .map_err(MempoolError::InvalidTransaction)?;
+
+        let now = SystemTime::now();
+        if self.is_expired(&tx, now) {
+            return Err(MempoolError::Expired);
+        }
+
+        self.insert(tx.clone(), fee);
+
+        self.evict(now);
+        if !self.transactions.contains(&tx) {
+            return Err(MempoolError::MempoolFull);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_rbf(&mut self, tx: Transaction, utxo_set: &UTXOSet,
+            height: u32, chain_id: u32, min_bump: f64)
+            -> Result<Vec<Sha256Hash>, MempoolError> {
+
+        if self.transactions.contains(&tx) {
+            return Err(MempoolError::AlreadyInMempool);
+        }
+
+        let conflicts = self.conflicts(&tx);
+        if conflicts.is_empty() {
+            return self.add(tx, utxo_set, height, chain_id)
+                .map(|_| Vec::new());
+        }
+
+        let fee = self.validate(&tx, utxo_set, height, chain_id)
+            .map_err(MempoolError::InvalidTransaction)?;
+        let fee_rate = fee as f64
+            / bincode::serialized_size(&tx).unwrap() as f64;
+
+        // The replacement has to pay for everything it evicts, descendants
+        // included, or a small bump on a parent could push out a large
+        // high-fee package.
+        let replaced = conflicts
+            .iter()
+            .flat_map(|conflict| self.descendants(conflict))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter_map(|tx_id| self.get(&tx_id).cloned())
+            .map(|tx| {
+                let fee = self.fee(&tx).unwrap_or(0);
+                (tx, fee)
+            })
+            .collect::<Vec<_>>();
+        let replaced_fee = replaced
+            .iter()
+            .map(|(_, fee)| *fee as u64)
+            .sum::<u64>();
+        if fee as u64 <= replaced_fee {
+            return Err(MempoolError::InsufficientFeeBump);
+        }
+
+        for conflict in &conflicts {
+            let old_tx = self.get(conflict).unwrap();
+            let old_fee_rate = self.fee(old_tx).unwrap_or(0) as f64
+                / bincode::serialized_size(old_tx).unwrap() as f64;
+
+            if fee_rate < old_fee_rate * min_bump {
+                return Err(MempoolError::InsufficientFeeBump);
+            }
+        }
+
+        if self.is_expired(&tx, SystemTime::now()) {
+            return Err(MempoolError::Expired);
+        }
+
+        let mut evicted = Vec::new();
+        for conflict in &conflicts {
+            evicted.extend(self.remove_with_descendants(conflict));
+        }
+
+        if let Err(err) = self.add(tx, utxo_set, height, chain_id) {
+            let fees = replaced
+                .iter()
+                .map(|(tx, fee)| (tx.calculate_id(), *fee))
+                .collect::<HashMap<_, _>>();
+            let restored = dependency_order(replaced.iter().map(|(tx, _)| tx))
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>();
+            for tx in restored {
+                let fee = fees[&tx.calculate_id()];
+                self.insert(tx, fee);
+            }
+            return Err(err);
+        }
+
+        Ok(evicted)
+    }
+
+    fn insert(&mut self, tx: Transaction, fee: u32) {
+        let tx_id = tx.calculate_id();
+        self.fees.insert(tx_id, fee);
+        for input in tx.inputs() {
+            let outpoint = (input.core.tx_id, input.core.output_id);
+            self.spent_outpoints.insert(outpoint, tx_id);
+        }
+        self.transactions.insert(tx);
+    }
+
+    // Validates against the confirmed set plus the outputs of transactions
+    // already in the mempool, so a child can follow its unconfirmed parent.
+    pub fn validate(&self, tx: &Transaction, utxo_set: &UTXOSet, height: u32,
+            chain_id: u32) -> Result<u32, TransactionValidityError> {
+
+        let mut view = UTXOSet::new();
+        for input in tx.inputs() {
+            let outpoint = (input.core.tx_id, input.core.output_id);
+            if let Some(entry) = utxo_set.get(&outpoint) {
+                view.insert(outpoint, entry.clone());
+            } else if let Some(parent) = self.get(&outpoint.0) {
+                let (_, output_id) = outpoint;
+                if let Some(output) = parent.outputs().get(output_id as usize) {
+                    view.insert(outpoint, UTXOEntry {
+                        output: output.clone(),
+                        height,
+                        is_coinbase: false
+                    });
+                }
+            }
+        }
+
+        tx.is_valid(&view, height, chain_id)
+    }
+
+    pub fn get(&self, tx_id: &Sha256Hash) -> Option<&Transaction> {
+        self.transactions
+            .iter()
+            .find(|tx| tx.calculate_id() == *tx_id)
+    }
+
+    pub fn remove_with_descendants(&mut self, tx_id: &Sha256Hash)
+            -> Vec<Sha256Hash> {
+
+        let removed = self.descendants(tx_id);
+        for tx_id in &removed {
+            if let Some(tx) = self.get(tx_id).cloned() {
+                self.remove(&tx);
+            }
+        }
+
+        removed
+    }
+
+    // The transaction itself and everything in the mempool spending from
+    // it, directly or through other mempool transactions.
+    fn descendants(&self, tx_id: &Sha256Hash) -> Vec<Sha256Hash> {
+        let mut found = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![*tx_id];
+        while let Some(tx_id) = pending.pop() {
+            let tx = match self.get(&tx_id) {
+                Some(tx) if seen.insert(tx_id) => tx,
+                _ => continue
+            };
+
+            for i in 0..tx.outputs().len() {
+                let outpoint = (tx_id, i as u32);
+                if let Some(child) = self.spent_outpoints.get(&outpoint) {
+                    pending.push(*child);
+                }
+            }
+            found.push(tx_id);
+        }
+
+        found
+    }
+
+    pub fn remove(&mut self, tx: &Transaction) -> bool {
+        let tx_id = tx.calculate_id();
+        self.fees.remove(&tx_id);
+        for input in tx.inputs() {
+            let outpoint = (input.core.tx_id, input.core.output_id);
+            if self.spent_outpoints.get(&outpoint) == Some(&tx_id) {
+                self.spent_outpoints.remove(&outpoint);
+            }
+        }
+
+        self.transactions.remove(tx)
+    }
+
+    pub fn conflicts(&self, tx: &Transaction) -> HashSet<Sha256Hash> {
+        tx.inputs()
+            .iter()
+            .filter_map(|input| self.spent_outpoints
+                .get(&(input.core.tx_id, input.core.output_id)))
+            .copied()
+            .collect()
+    }
+
+    pub fn fee(&self, tx: &Transaction) -> Option<u32> {
+        self.fees.get(&tx.calculate_id()).copied()
+    }
+
+    pub fn evict(&mut self, now: SystemTime) {
+        let expired = self.transactions
+            .iter()
+            .filter(|tx| self.is_expired(tx, now))
+            .cloned()
+            .collect::<Vec<_>>();
+        for tx in &expired {
+            self.remove_with_descendants(&tx.calculate_id());
+        }
+
+        while self.transactions.len() > self.max_count {
+            let lowest_fee_rate = self.transactions
+                .iter()
+                .map(|tx| (tx, self.fee(tx).unwrap_or(0) as u64,
+                    bincode::serialized_size(tx).unwrap()))
+                .min_by(|(_, fee_a, size_a), (_, fee_b, size_b)| {
+                    (fee_a * size_b).cmp(&(fee_b * size_a))
+                })
+                .map(|(tx, _, _)| tx.calculate_id());
+
+            match lowest_fee_rate {
+                Some(tx_id) => self.remove_with_descendants(&tx_id),
+                None => break
+            };
+        }
+    }
+
+    fn is_expired(&self, tx: &Transaction, now: SystemTime) -> bool {
+        match now.duration_since(tx.time_stamp) {
+            Ok(age) => age > self.max_age,
+            Err(_) => false
+        }
+    }
+}
+
+// Orders transactions so that every parent comes before the children that
+// spend its outputs. Transactions caught in a cycle are left out.
+pub fn dependency_order<'a, I>(txs: I) -> Vec<&'a Transaction>
+    where I: IntoIterator<Item = &'a Transaction>
+{
+    let mut txs = txs.into_iter().collect::<Vec<_>>();
+    txs.sort_by_key(|tx| (tx.time_stamp, tx.calculate_id()));
+
+    let by_id = txs
+        .iter()
+        .map(|tx| (tx.calculate_id(), *tx))
+        .collect::<HashMap<_, _>>();
+
+    let mut parent_count = HashMap::new();
+    let mut children = HashMap::<Sha256Hash, Vec<Sha256Hash>>::new();
+    for (tx_id, tx) in txs.iter().map(|tx| (tx.calculate_id(), tx)) {
+        let parents = tx.inputs()
+            .iter()
+            .map(|input| input.core.tx_id)
+            .filter(|parent| by_id.contains_key(parent))
+            .collect::<HashSet<_>>();
+        for parent in &parents {
+            children.entry(*parent).or_default().push(tx_id);
+        }
+        parent_count.insert(tx_id, parents.len());
+    }
+
+    let mut ready = txs
+        .iter()
+        .map(|tx| tx.calculate_id())
+        .filter(|tx_id| parent_count[tx_id] == 0)
+        .collect::<VecDeque<_>>();
+
+    let mut ordered = Vec::new();
+    while let Some(tx_id) = ready.pop_front() {
+        ordered.push(by_id[&tx_id]);
+        for child in children.remove(&tx_id).unwrap_or_default() {
+            let count = parent_count.get_mut(&child).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push_back(child);
+            }
+        }
+    }
+
+    ordered
+}
+
+// Maps every transaction to its package: itself plus all of its ancestors
+// within the set. Ranking packages by their combined fee rate lets a
+// high-fee child pay for a low-fee parent.
+pub fn ancestor_packages(txs: &[&Transaction])
+        -> HashMap<Sha256Hash, HashSet<Sha256Hash>> {
+
+    let mut packages = HashMap::<Sha256Hash, HashSet<Sha256Hash>>::new();
+    for tx in dependency_order(txs.iter().copied()) {
+        let tx_id = tx.calculate_id();
+        let mut package = HashSet::from([tx_id]);
+        for input in tx.inputs() {
+            if let Some(ancestors) = packages.get(&input.core.tx_id) {
+                package.extend(ancestors.iter().copied());
+            }
+        }
+        packages.insert(tx_id, package);
+    }
+
+    packages
+}
+
+impl std::ops::Deref for Mempool {
+    type Target = HashSet<Transaction>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.transactions
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testutil::{fund, make_keypair, spend};
+    use crate::blockchain::transaction::{
+        sighash_preimage,
+        Input,
+        Output,
+        DEFAULT_CHAIN_ID
+    };
+
+    const MAX_AGE: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn child_of_unconfirmed_parent_is_admitted() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let parent = spend(&key, funded, &[(pubkey, 90)]);
+        let child = spend(&key, (parent.calculate_id(), 0), &[(pubkey, 85)]);
+
+        let mut mempool = Mempool::new(10, MAX_AGE);
+        assert!(matches!(
+            mempool.add(child.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID),
+            Err(MempoolError::InvalidTransaction(_))));
+
+        mempool.add(parent, &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+        mempool.add(child.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(mempool.fee(&child), Some(5));
+    }
+
+    #[test]
+    fn evicting_a_parent_evicts_its_children() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let (_, other) = fund(&mut utxo_set, &pubkey, 100);
+        let parent = spend(&key, funded, &[(pubkey, 99)]);
+        let child = spend(&key, (parent.calculate_id(), 0), &[(pubkey, 89)]);
+        let unrelated = spend(&key, other, &[(pubkey, 50)]);
+
+        let mut mempool = Mempool::new(2, MAX_AGE);
+        mempool.add(parent, &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+        mempool.add(child.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+        assert!(matches!(
+            mempool.add(unrelated.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID),
+            Ok(())));
+
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&unrelated));
+        assert!(!mempool.contains(&child));
+    }
+
+    #[test]
+    fn second_spend_of_an_outpoint_is_rejected() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let first = spend(&key, funded, &[(pubkey, 90)]);
+        let second = spend(&key, funded, &[(pubkey, 80)]);
+
+        let mut mempool = Mempool::new(10, MAX_AGE);
+        mempool.add(first.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+        assert!(matches!(
+            mempool.add(second.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID),
+            Err(MempoolError::Conflict(id)) if id == first.calculate_id()));
+
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&first));
+        assert!(!mempool.contains(&second));
+    }
+
+    #[test]
+    fn replacement_must_outbid_the_evicted_package() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 1000);
+        let parent = spend(&key, funded, &[(pubkey, 990)]);
+        let child = spend(&key, (parent.calculate_id(), 0), &[(pubkey, 500)]);
+        let bump = spend(&key, funded, &[(pubkey, 970)]);
+
+        let mut mempool = Mempool::new(10, MAX_AGE);
+        mempool.add(parent.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+        mempool.add(child.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID).unwrap();
+
+        // Twice the parent's fee, but far less than parent and child pay.
+        assert!(matches!(mempool.add_rbf(bump, &utxo_set, 1,
+            DEFAULT_CHAIN_ID, 1.25), Err(MempoolError::InsufficientFeeBump)));
+        assert!(mempool.contains(&parent));
+        assert!(mempool.contains(&child));
+    }
+
+    #[test]
+    fn failed_replacement_restores_the_evicted() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let original = spend(&key, funded, &[(pubkey, 90)]);
+
+        // Spends the original's output too, so it stops being valid once
+        // the original is evicted.
+        let inputs = [
+            Input::new().set_tx_id(&funded.0).set_utxo_id(funded.1),
+            Input::new().set_tx_id(&original.calculate_id()).set_utxo_id(0)
+        ];
+        let output = Output::new().set_pubkey(pubkey).set_amount(10).collect();
+        let cores = inputs.iter().map(|input| input.core()).collect::<Vec<_>>();
+        let sighash = sighash_preimage(DEFAULT_CHAIN_ID, 0, &cores,
+            std::slice::from_ref(&output));
+        let mut replacement = Transaction::new();
+        for input in inputs {
+            replacement.add_input(input.sign_with_pubkey(&key, &sighash));
+        }
+        replacement.add_output(output);
+
+        let mut mempool = Mempool::new(10, MAX_AGE);
+        mempool.add(original.clone(), &utxo_set, 1, DEFAULT_CHAIN_ID)
+            .unwrap();
+        assert!(matches!(mempool.add_rbf(replacement.clone(), &utxo_set, 1,
+            DEFAULT_CHAIN_ID, 1.25), Err(MempoolError::InvalidTransaction(_))));
+
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&original));
+        assert_eq!(mempool.fee(&original), Some(10));
+        assert_eq!(mempool.conflicts(&replacement),
+            HashSet::from([original.calculate_id()]));
+    }
+}
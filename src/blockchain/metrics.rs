@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex
+    }
+};
+
+use super::block::BlockValidityError;
+
+
+const METRICS_PREFIX: &str = "rustychain";
+
+
+#[derive(Debug)]
+pub struct Metrics {
+    blocks_mined: AtomicU64,
+    blocks_received: AtomicU64,
+    blocks_rejected: Mutex<BTreeMap<String, u64>>,
+    mempool_size: AtomicU64,
+    peer_count: AtomicU64,
+    difficulty: AtomicU64
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            blocks_mined: AtomicU64::new(0),
+            blocks_received: AtomicU64::new(0),
+            blocks_rejected: Mutex::new(BTreeMap::new()),
+            mempool_size: AtomicU64::new(0),
+            peer_count: AtomicU64::new(0),
+            difficulty: AtomicU64::new(0)
+        }
+    }
+
+    pub fn record_mined_block(&self) {
+        self.blocks_mined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_received_block(&self) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_block(&self, reason: &BlockValidityError) {
+        let reason = format!("{:?}", reason);
+        *self.blocks_rejected
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_insert(0) += 1;
+    }
+
+    pub fn set_mempool_size(&self, size: usize) {
+        self.mempool_size.store(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_peer_count(&self, count: usize) {
+        self.peer_count.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_difficulty(&self, difficulty: u32) {
+        self.difficulty.store(difficulty as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let sample = |value: &AtomicU64| {
+            vec![(String::new(), value.load(Ordering::Relaxed))]
+        };
+
+        render_metric(&mut out, "blocks_mined_total", "counter",
+            &sample(&self.blocks_mined));
+        render_metric(&mut out, "blocks_received_total", "counter",
+            &sample(&self.blocks_received));
+
+        let rejected = self.blocks_rejected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reason, count)| (format!("reason=\"{}\"", reason), *count))
+            .collect::<Vec<_>>();
+        render_metric(&mut out, "blocks_rejected_total", "counter", &rejected);
+
+        render_metric(&mut out, "mempool_size", "gauge",
+            &sample(&self.mempool_size));
+        render_metric(&mut out, "peer_count", "gauge",
+            &sample(&self.peer_count));
+        render_metric(&mut out, "difficulty", "gauge",
+            &sample(&self.difficulty));
+
+        out
+    }
+}
+
+fn render_metric(out: &mut String, name: &str, metric_type: &str,
+        samples: &[(String, u64)]) {
+
+    let _ = writeln!(out, "# TYPE {}_{} {}", METRICS_PREFIX, name,
+        metric_type);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{}_{} {}", METRICS_PREFIX, name, value);
+        } else {
+            let _ = writeln!(out, "{}_{}{{{}}} {}", METRICS_PREFIX, name,
+                labels, value);
+        }
+    }
+}
@@ -1,4 +1,12 @@
+pub mod address;
 pub mod block;
+pub mod bloom;
 pub mod global_state;
+pub mod hex_serde;
+pub mod mempool;
+pub mod metrics;
+pub mod orphans;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
 pub mod transaction;
-
+pub mod wallet;
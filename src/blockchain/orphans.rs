@@ -0,0 +1,75 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::block::Block;
+use super::transaction::Sha256Hash;
+
+
+#[derive(Debug)]
+pub struct OrphanPool {
+    blocks: HashMap<Sha256Hash, Block>,
+    by_parent: HashMap<Sha256Hash, Vec<Sha256Hash>>,
+    arrival_order: VecDeque<Sha256Hash>,
+    max_count: usize
+}
+
+impl OrphanPool {
+    pub fn new(max_count: usize) -> Self {
+        OrphanPool {
+            blocks: HashMap::new(),
+            by_parent: HashMap::new(),
+            arrival_order: VecDeque::new(),
+            max_count
+        }
+    }
+
+    pub fn add(&mut self, block: Block) -> bool {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return false;
+        }
+
+        self.by_parent
+            .entry(block.header.previous_block)
+            .or_default()
+            .push(hash);
+        self.blocks.insert(hash, block);
+        self.arrival_order.push_back(hash);
+
+        while self.blocks.len() > self.max_count {
+            match self.arrival_order.pop_front() {
+                Some(oldest) => self.remove(&oldest),
+                None => break
+            };
+        }
+
+        self.blocks.contains_key(&hash)
+    }
+
+    pub fn take_children(&mut self, parent: &Sha256Hash) -> Vec<Block> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        self.arrival_order.retain(|hash| !children.contains(hash));
+
+        children
+            .iter()
+            .filter_map(|hash| self.blocks.remove(hash))
+            .collect()
+    }
+
+    pub fn get(&self, hash: &Sha256Hash) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn remove(&mut self, hash: &Sha256Hash) -> Option<Block> {
+        let block = self.blocks.remove(hash)?;
+        self.arrival_order.retain(|queued| queued != hash);
+        let parent = block.header.previous_block;
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+
+        Some(block)
+    }
+}
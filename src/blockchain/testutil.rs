@@ -0,0 +1,93 @@
+use std::{env, fs, path::{Path, PathBuf}};
+
+use k256::ecdsa::{SigningKey, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+
+use super::transaction::{
+    sighash_preimage,
+    Input,
+    Output,
+    Sha256Hash,
+    Transaction,
+    UTXOEntry,
+    UTXOSet,
+    DEFAULT_CHAIN_ID
+};
+
+
+pub fn make_keypair() -> (SigningKey, VerifyingKey) {
+    let key = SigningKey::random(&mut OsRng);
+    let pubkey = *key.verifying_key();
+    (key, pubkey)
+}
+
+// Adds a confirmed, non-coinbase output paying `amount` to `pubkey` under a
+// random transaction id, so it is spendable at any height.
+pub fn fund(utxo_set: &mut UTXOSet, pubkey: &VerifyingKey, amount: u32)
+        -> (Sha256Hash, (Sha256Hash, u32)) {
+
+    let mut tx_id = [0u8; 32];
+    OsRng.fill_bytes(&mut tx_id);
+
+    let outpoint = (tx_id, 0);
+    utxo_set.insert(outpoint, UTXOEntry {
+        output: Output::new()
+            .set_pubkey(*pubkey)
+            .set_amount(amount)
+            .collect(),
+        height: 0,
+        is_coinbase: false
+    });
+
+    (tx_id, outpoint)
+}
+
+// Spends a single outpoint to `recipients`. Whatever the outputs leave of
+// the funded amount is the fee.
+pub fn spend(signing_key: &SigningKey, outpoint: (Sha256Hash, u32),
+        recipients: &[(VerifyingKey, u32)]) -> Transaction {
+
+    let (tx_id, output_id) = outpoint;
+    let input = Input::new()
+        .set_tx_id(&tx_id)
+        .set_utxo_id(output_id);
+    let outputs = recipients
+        .iter()
+        .map(|(pubkey, amount)| Output::new()
+            .set_pubkey(*pubkey)
+            .set_amount(*amount)
+            .collect())
+        .collect::<Vec<_>>();
+    let sighash = sighash_preimage(DEFAULT_CHAIN_ID, 0, &[input.core()],
+        &outputs);
+
+    let mut tx = Transaction::new();
+    tx.add_input(input.sign_with_pubkey(signing_key, &sighash));
+    for output in outputs {
+        tx.add_output(output);
+    }
+
+    tx
+}
+
+// A fresh directory under the system temp dir, removed again on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = env::temp_dir()
+            .join(format!("rustychain-test-{:08x}", OsRng.next_u32()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
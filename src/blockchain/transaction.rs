@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
     time::SystemTime
 };
@@ -14,36 +14,92 @@ use k256::{
 };
 use serde::{Deserialize, Serialize};
 
+use super::address::{
+    address_to_pubkey_hash,
+    pubkey_hash,
+    AddressError,
+    PubkeyHash
+};
+use super::hex_serde;
+
 
 pub type Sha256Hash = [u8; 32];
-pub type UTXOSet = HashMap<(Sha256Hash, u32), Output>;
+pub type UTXOSet = HashMap<(Sha256Hash, u32), UTXOEntry>;
+
+pub const COINBASE_MATURITY: u32 = 100;
+pub const DEFAULT_CHAIN_ID: u32 = 1;
+const SHORT_HASH_LEN: usize = 8;
+
+pub fn hash_to_hex(hash: &Sha256Hash) -> String {
+    hex::encode(hash)
+}
+
+pub fn short_hash(hash: &Sha256Hash) -> String {
+    hex::encode(&hash[..SHORT_HASH_LEN])
+}
+
+pub fn short_tx_id(tx_id: &Sha256Hash) -> u64 {
+    u64::from_le_bytes(tx_id[..8].try_into().unwrap())
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum OutputLock {
+    Pubkey(#[serde(with = "hex_serde::verifying_key")] VerifyingKey),
+    PubkeyHash(PubkeyHash)
+}
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Output {
-    to_pubkey: VerifyingKey,
+    lock: OutputLock,
     amount: u32
 }
 
 pub struct PartialOutput {
-    to_pubkey: Option<VerifyingKey>,
+    lock: Option<OutputLock>,
     amount: Option<u32>
 }
 
 impl Output {
     pub fn new() -> PartialOutput {
         PartialOutput {
-            to_pubkey: None,
+            lock: None,
             amount: None
         }
     }
+
+    pub fn amount(&self) -> u32 {
+        self.amount
+    }
+
+    pub fn lock(&self) -> &OutputLock {
+        &self.lock
+    }
+
+    pub fn is_owned_by(&self, key: &VerifyingKey, key_hash: &PubkeyHash)
+            -> bool {
+
+        match &self.lock {
+            OutputLock::Pubkey(to_pubkey) => to_pubkey == key,
+            OutputLock::PubkeyHash(to_pubkey_hash) => to_pubkey_hash == key_hash
+        }
+    }
 }
 
 impl PartialOutput {
     pub fn set_pubkey(mut self, key: VerifyingKey) -> Self {
-        self.to_pubkey = Some(key);
+        self.lock = Some(OutputLock::Pubkey(key));
+        self
+    }
+
+    pub fn set_pubkey_hash(mut self, key_hash: PubkeyHash) -> Self {
+        self.lock = Some(OutputLock::PubkeyHash(key_hash));
         self
     }
 
+    pub fn set_address(self, address: &str) -> Result<Self, AddressError> {
+        Ok(self.set_pubkey_hash(address_to_pubkey_hash(address)?))
+    }
+
     pub fn set_amount(mut self, amount: u32) -> Self {
         self.amount = Some(amount);
         self
@@ -51,8 +107,8 @@ impl PartialOutput {
 
     pub fn collect(self) -> Output {
         Output {
-            to_pubkey: self.to_pubkey
-                .expect("Pubkey needs to be defined to collect"),
+            lock: self.lock
+                .expect("Pubkey or pubkey hash needs to be defined to collect"),
             amount: self.amount
                 .expect("Amount needs to be defined to collect")
         }
@@ -60,6 +116,28 @@ impl PartialOutput {
 }
 
 
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UTXOEntry {
+    pub output: Output,
+    pub height: u32,
+    pub is_coinbase: bool
+}
+
+impl UTXOEntry {
+    // Whether a transaction in a block at `height` may spend this output.
+    pub fn is_mature(&self, height: u32) -> bool {
+        !self.is_coinbase
+            || height.saturating_sub(self.height) >= COINBASE_MATURITY
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum UTXODelta {
+    Insert((Sha256Hash, u32), UTXOEntry),
+    Remove((Sha256Hash, u32))
+}
+
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct InputCore {
     pub tx_id: Sha256Hash,
@@ -69,6 +147,9 @@ pub struct InputCore {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Input {
     pub core: InputCore,
+    #[serde(with = "hex_serde::optional_verifying_key")]
+    pubkey: Option<VerifyingKey>,
+    #[serde(with = "hex_serde::signature")]
     signature: Signature
 }
 
@@ -86,9 +167,17 @@ impl Input {
         }
     }
 
-    pub fn verify(&self, pub_key: VerifyingKey) -> bool {
-        let serialized_core = bincode::serialize(&self.core).unwrap();
-        pub_key.verify(&serialized_core, &self.signature).is_ok()
+    pub fn verify(&self, pub_key: VerifyingKey, sighash: &[u8]) -> bool {
+        pub_key.verify(sighash, &self.signature).is_ok()
+    }
+
+    pub fn spending_key(&self, lock: &OutputLock) -> Option<VerifyingKey> {
+        match (lock, self.pubkey) {
+            (OutputLock::Pubkey(key), _) => Some(*key),
+            (OutputLock::PubkeyHash(key_hash), Some(key))
+                if pubkey_hash(&key) == *key_hash => Some(key),
+            (OutputLock::PubkeyHash(_), _) => None
+        }
     }
 }
 
@@ -103,58 +192,121 @@ impl PartialInput {
         self
     }
 
-    pub fn sign(self, key: &SigningKey) -> Input {
-        let core = InputCore {
+    pub fn core(&self) -> InputCore {
+        InputCore {
             tx_id: self.tx_id
                 .expect("Transaction id needs to be defined to sign"),
             output_id: self.output_id
                 .expect("Output id needs to be defined to sign")
-        };
+        }
+    }
+
+    pub fn sign(self, key: &SigningKey, sighash: &[u8]) -> Input {
+        self.sign_with(key, None, sighash)
+    }
+
+    pub fn sign_with_pubkey(self, key: &SigningKey, sighash: &[u8]) -> Input {
+        self.sign_with(key, Some(*key.verifying_key()), sighash)
+    }
+
+    fn sign_with(self, key: &SigningKey, pubkey: Option<VerifyingKey>,
+            sighash: &[u8]) -> Input {
 
-        let serialized_core = bincode::serialize(&core).unwrap();
-        let signature = key.sign(&serialized_core);
+        let core = self.core();
+        let signature = key.sign(sighash);
 
         Input {
             core,
+            pubkey,
             signature
         }
     }
 }
 
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "TransactionData")]
 pub struct Transaction {
+    pub time_stamp: SystemTime,
+    locktime: u32,
+    inputs: Vec<Input>,
+    outputs: Vec<Output>,
+    #[serde(skip)]
+    id: Sha256Hash
+}
+
+#[derive(Deserialize)]
+struct TransactionData {
     time_stamp: SystemTime,
-    pub inputs: Vec<Input>,
-    pub outputs: Vec<Output>
+    locktime: u32,
+    inputs: Vec<Input>,
+    outputs: Vec<Output>
 }
 
 #[derive(Debug)]
 pub enum TransactionValidityError {
     InvalidOutputAmount(u32),
     InvalidSignature(u32),
-    InputDoesNotExist(u32)
+    InputDoesNotExist(u32),
+    DuplicateInput(u32),
+    Locked(u32),
+    ImmatureCoinbase(u32),
+    PubkeyHashMismatch(u32),
+    AmountOverflow
 }
 
 impl Transaction {
     pub fn new() -> Self {
-        Transaction {
+        Transaction::from(TransactionData {
             time_stamp: SystemTime::now(),
+            locktime: 0,
             inputs: Vec::new(),
             outputs: Vec::new()
-        }
+        })
     }
 
     pub fn add_input(&mut self, input: Input) {
         self.inputs.push(input);
+        self.id = self.compute_id();
     }
 
     pub fn add_output(&mut self, output: Output) {
         self.outputs.push(output);
+        self.id = self.compute_id();
+    }
+
+    pub fn set_locktime(&mut self, locktime: u32) {
+        self.locktime = locktime;
+        self.id = self.compute_id();
+    }
+
+    pub fn locktime(&self) -> u32 {
+        self.locktime
+    }
+
+    pub fn inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
     }
 
     pub fn calculate_id(&self) -> Sha256Hash {
-        let serialized_tx = bincode::serialize(self).unwrap();
+        self.id
+    }
+
+    fn compute_id(&self) -> Sha256Hash {
+        let content = (self.locktime, &self.inputs, &self.outputs);
+        let serialized_tx = bincode::serialize(&content).unwrap();
         let mut hasher = Sha256::new();
         hasher.update(serialized_tx);
         hasher
@@ -164,30 +316,75 @@ impl Transaction {
             .expect("Wrong len")
     }
 
-    pub fn is_valid(&self, utxo_set: &UTXOSet)
+    pub fn sighash(&self, chain_id: u32) -> Vec<u8> {
+        let cores = self.inputs
+            .iter()
+            .map(|input| input.core.clone())
+            .collect::<Vec<_>>();
+        sighash_preimage(chain_id, self.locktime, &cores, &self.outputs)
+    }
+
+    pub fn is_valid(&self, utxo_set: &UTXOSet, height: u32, chain_id: u32)
             -> Result<u32, TransactionValidityError> {
 
+        self.validate(utxo_set, height, chain_id, true)
+    }
+
+    // Skipping signature checks is only sound for history that is already
+    // known to be valid, such as blocks below an assume-valid checkpoint.
+    pub fn validate(&self, utxo_set: &UTXOSet, height: u32, chain_id: u32,
+            verify_signatures: bool) -> Result<u32, TransactionValidityError> {
+
+        if self.locktime > height {
+            return Err(TransactionValidityError::Locked(self.locktime));
+        }
+
         let total_output = self.outputs
             .iter()
-            .fold(0, |acc, val| acc + val.amount);
+            .try_fold(0u32, |acc, val| acc.checked_add(val.amount))
+            .ok_or(TransactionValidityError::AmountOverflow)?;
 
-        let mut total_input = 0;
+        let sighash = self.sighash(chain_id);
+        let mut total_input = 0u32;
+        let mut spent_outpoints = HashSet::<(Sha256Hash, u32)>::new();
         for (i, input) in self.inputs.iter().enumerate() {
-            let utxo = match utxo_set.get(
+            let outpoint = (input.core.tx_id, input.core.output_id);
+            if !spent_outpoints.insert(outpoint) {
+                return Err(TransactionValidityError::DuplicateInput(i as u32));
+            }
+
+            let entry = match utxo_set.get(
                     &(input.core.tx_id, input.core.output_id)) {
-                Some(utxo) => utxo,
+                Some(entry) => entry,
                 None => return Err(
                     TransactionValidityError::InputDoesNotExist(i as u32)
                 )
             };
 
-            if !input.verify(utxo.to_pubkey) {
+            if !entry.is_mature(height) {
+                return Err(
+                    TransactionValidityError::ImmatureCoinbase(i as u32)
+                )
+            }
+
+            let utxo = &entry.output;
+
+            let spending_key = match input.spending_key(&utxo.lock) {
+                Some(key) => key,
+                None => return Err(
+                    TransactionValidityError::PubkeyHashMismatch(i as u32)
+                )
+            };
+
+            if verify_signatures && !input.verify(spending_key, &sighash) {
                 return Err(
                     TransactionValidityError::InvalidSignature(i as u32)
                 )
             }
 
-            total_input += utxo.amount;
+            total_input = total_input
+                .checked_add(utxo.amount)
+                .ok_or(TransactionValidityError::AmountOverflow)?;
         }
 
         if total_output <= total_input {
@@ -198,16 +395,400 @@ impl Transaction {
             total_output - total_input))
     }
 
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
     pub fn update_time(&mut self) {
         self.time_stamp = SystemTime::now();
     }
 }
 
+pub struct TransactionBuilder {
+    recipients: Vec<(VerifyingKey, u32)>,
+    fee: u32,
+    locktime: u32,
+    chain_id: u32
+}
+
+#[derive(Debug)]
+pub enum TransactionBuildError {
+    InsufficientFunds(u32),
+    AmountOverflow
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        TransactionBuilder {
+            recipients: Vec::new(),
+            fee: 0,
+            locktime: 0,
+            chain_id: DEFAULT_CHAIN_ID
+        }
+    }
+
+    pub fn add_recipient(mut self, key: VerifyingKey, amount: u32) -> Self {
+        self.recipients.push((key, amount));
+        self
+    }
+
+    pub fn set_fee(mut self, fee: u32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn set_locktime(mut self, locktime: u32) -> Self {
+        self.locktime = locktime;
+        self
+    }
+
+    pub fn set_chain_id(mut self, chain_id: u32) -> Self {
+        self.chain_id = chain_id;
+        self
+    }
+
+    // `height` is the height of the block the transaction is meant for, so
+    // coinbase outputs that are still immature there are never selected.
+    pub fn build(self, key: &SigningKey, utxo_set: &UTXOSet, height: u32)
+            -> Result<Transaction, TransactionBuildError> {
+
+        let sender = *key.verifying_key();
+        let target = self.recipients
+            .iter()
+            .try_fold(self.fee, |acc, (_, amount)| acc.checked_add(*amount))
+            .ok_or(TransactionBuildError::AmountOverflow)?;
+
+        let (outpoints, change) = select_coins(utxo_set, &sender, target,
+                height)
+            .ok_or_else(|| TransactionBuildError::InsufficientFunds(
+                target.saturating_sub(spendable_balance(utxo_set, &sender,
+                    height))))?;
+
+        let mut outputs = self.recipients
+            .into_iter()
+            .map(|(to_pubkey, amount)| Output::new()
+                .set_pubkey(to_pubkey)
+                .set_amount(amount)
+                .collect())
+            .collect::<Vec<_>>();
+
+        if change > 0 {
+            outputs.push(Output::new()
+                .set_pubkey(sender)
+                .set_amount(change)
+                .collect());
+        }
+
+        let inputs = outpoints
+            .iter()
+            .map(|(tx_id, output_id)| Input::new()
+                .set_tx_id(tx_id)
+                .set_utxo_id(*output_id))
+            .collect::<Vec<_>>();
+        let cores = inputs
+            .iter()
+            .map(|input| input.core())
+            .collect::<Vec<_>>();
+        let sighash = sighash_preimage(self.chain_id, self.locktime, &cores,
+            &outputs);
+
+        let mut tx = Transaction::new();
+        tx.set_locktime(self.locktime);
+        for (input, outpoint) in inputs.into_iter().zip(&outpoints) {
+            match utxo_set[outpoint].output.lock {
+                OutputLock::Pubkey(_) =>
+                    tx.add_input(input.sign(key, &sighash)),
+                OutputLock::PubkeyHash(_) =>
+                    tx.add_input(input.sign_with_pubkey(key, &sighash))
+            }
+        }
+
+        for output in outputs {
+            tx.add_output(output);
+        }
+
+        Ok(tx)
+    }
+}
+
+
+// Everything the key owns, including coinbase outputs that are not yet
+// spendable.
+pub fn balance_of(utxo_set: &UTXOSet, pubkey: &VerifyingKey) -> u32 {
+    let key_hash = pubkey_hash(pubkey);
+    let amounts = utxo_set
+        .values()
+        .filter(|entry| entry.output.is_owned_by(pubkey, &key_hash))
+        .map(|entry| entry.output.amount);
+    saturating_total(amounts)
+}
+
+pub fn spendable_balance(utxo_set: &UTXOSet, pubkey: &VerifyingKey,
+        height: u32) -> u32 {
+
+    let amounts = spendable_outpoints(utxo_set, pubkey, height)
+        .into_iter()
+        .map(|(_, _, amount)| amount);
+    saturating_total(amounts)
+}
+
+// Amounts are summed wide so a key holding more than u32::MAX in total
+// reports the largest balance instead of overflowing.
+fn saturating_total(amounts: impl Iterator<Item = u32>) -> u32 {
+    let total = amounts.map(u64::from).sum::<u64>();
+    u32::try_from(total).unwrap_or(u32::MAX)
+}
+
+pub fn spendable_outpoints(utxo_set: &UTXOSet, pubkey: &VerifyingKey,
+        height: u32) -> Vec<(Sha256Hash, u32, u32)> {
+
+    let key_hash = pubkey_hash(pubkey);
+    utxo_set
+        .iter()
+        .filter(|(_, entry)| entry.is_mature(height)
+            && entry.output.is_owned_by(pubkey, &key_hash))
+        .map(|((tx_id, output_id), entry)|
+            (*tx_id, *output_id, entry.output.amount))
+        .collect()
+}
+
+pub fn select_coins(utxo_set: &UTXOSet, owner: &VerifyingKey, target: u32,
+        height: u32) -> Option<(Vec<(Sha256Hash, u32)>, u32)> {
+
+    let mut coins = spendable_outpoints(utxo_set, owner, height);
+    coins.sort_by(|(id_a, out_a, amount_a), (id_b, out_b, amount_b)| {
+        amount_b.cmp(amount_a).then((id_a, out_a).cmp(&(id_b, out_b)))
+    });
+
+    let single_coin = coins
+        .iter()
+        .rfind(|(_, _, amount)| *amount >= target);
+    if let Some((tx_id, output_id, amount)) = single_coin {
+        return Some((vec![(*tx_id, *output_id)], amount - target));
+    }
+
+    let mut selected = 0;
+    let mut total = 0u64;
+    while total < target as u64 {
+        let (_, _, amount) = coins.get(selected)?;
+        total += *amount as u64;
+        selected += 1;
+    }
+
+    // Only a zero target is met without any coin.
+    if selected == 0 {
+        return Some((Vec::new(), 0));
+    }
+
+    let last_amount = coins[selected - 1].2 as u64;
+    let shortfall = target as u64 - (total - last_amount);
+    let replacement = coins[selected..]
+        .iter()
+        .rposition(|(_, _, amount)| *amount as u64 >= shortfall);
+    if let Some(i) = replacement {
+        total = total - last_amount + coins[selected + i].2 as u64;
+        coins.swap(selected - 1, selected + i);
+    }
+
+    let outpoints = coins[..selected]
+        .iter()
+        .map(|(tx_id, output_id, _)| (*tx_id, *output_id))
+        .collect();
+    Some((outpoints, (total - target as u64) as u32))
+}
+
+// Every input signs the same preimage, committing to all outpoints and all
+// outputs on one chain. Integers are little-endian:
+//   chain_id u32
+//   locktime u32
+//   input count u32, then per input: tx_id [32 bytes], output_id u32
+//   output count u32, then per output:
+//     0x00 + SEC1 compressed pubkey [33 bytes], or 0x01 + pubkey hash
+//     [20 bytes], followed by amount u32
+// The signature is ECDSA over SHA-256 of this preimage.
+pub fn sighash_preimage(chain_id: u32, locktime: u32, inputs: &[InputCore],
+        outputs: &[Output]) -> Vec<u8> {
+
+    let mut preimage = Vec::new();
+    preimage.extend(chain_id.to_le_bytes());
+    preimage.extend(locktime.to_le_bytes());
+
+    preimage.extend((inputs.len() as u32).to_le_bytes());
+    for input in inputs {
+        preimage.extend(input.tx_id);
+        preimage.extend(input.output_id.to_le_bytes());
+    }
+
+    preimage.extend((outputs.len() as u32).to_le_bytes());
+    for output in outputs {
+        match &output.lock {
+            OutputLock::Pubkey(key) => {
+                preimage.push(0);
+                preimage.extend(key.to_encoded_point(true).as_bytes());
+            }
+            OutputLock::PubkeyHash(key_hash) => {
+                preimage.push(1);
+                preimage.extend(key_hash);
+            }
+        }
+        preimage.extend(output.amount.to_le_bytes());
+    }
+
+    preimage
+}
+
+impl From<TransactionData> for Transaction {
+    fn from(data: TransactionData) -> Self {
+        let mut tx = Transaction {
+            time_stamp: data.time_stamp,
+            locktime: data.locktime,
+            inputs: data.inputs,
+            outputs: data.outputs,
+            id: [0u8; 32]
+        };
+        tx.id = tx.compute_id();
+        tx
+    }
+}
+
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Self) -> bool {
+        self.locktime == other.locktime
+            && self.inputs == other.inputs
+            && self.outputs == other.outputs
+    }
+}
+
+impl Eq for Transaction {}
+
 impl Hash for Transaction {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        let serialized_self =  bincode::serialize(self).unwrap();
-        let hash = Sha256::digest(&serialized_self);
-        state.write(&hash);
+        state.write(&self.calculate_id());
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testutil::{fund, make_keypair, spend};
+
+    fn fund_coinbase(utxo_set: &mut UTXOSet, pubkey: &VerifyingKey,
+            amount: u32, height: u32) -> (Sha256Hash, u32) {
+
+        let (_, outpoint) = fund(utxo_set, pubkey, amount);
+        let entry = utxo_set.get_mut(&outpoint).unwrap();
+        entry.height = height;
+        entry.is_coinbase = true;
+        outpoint
+    }
+
+    #[test]
+    fn builder_skips_immature_coinbase() {
+        let (key, pubkey) = make_keypair();
+        let (_, recipient) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let immature = fund_coinbase(&mut utxo_set, &pubkey, 1000, 50);
+        let (_, mature) = fund(&mut utxo_set, &pubkey, 100);
+
+        let tx = TransactionBuilder::new()
+            .add_recipient(recipient, 60)
+            .set_fee(5)
+            .build(&key, &utxo_set, 100)
+            .unwrap();
+
+        assert_eq!(tx.inputs().len(), 1);
+        assert_eq!(tx.inputs()[0].core.tx_id, mature.0);
+        assert_ne!(tx.inputs()[0].core.tx_id, immature.0);
+        assert_eq!(tx.is_valid(&utxo_set, 100, DEFAULT_CHAIN_ID).unwrap(), 5);
+    }
+
+    #[test]
+    fn builder_reports_shortfall_of_spendable_coins() {
+        let (key, pubkey) = make_keypair();
+        let (_, recipient) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        fund_coinbase(&mut utxo_set, &pubkey, 1000, 50);
+        fund(&mut utxo_set, &pubkey, 100);
+
+        let res = TransactionBuilder::new()
+            .add_recipient(recipient, 500)
+            .build(&key, &utxo_set, 100);
+        assert!(matches!(res,
+            Err(TransactionBuildError::InsufficientFunds(400))));
+
+        let tx = TransactionBuilder::new()
+            .add_recipient(recipient, 500)
+            .build(&key, &utxo_set, 150)
+            .unwrap();
+        assert_eq!(tx.is_valid(&utxo_set, 150, DEFAULT_CHAIN_ID).unwrap(), 0);
+    }
+
+    #[test]
+    fn json_view_round_trips_to_the_same_bincode() {
+        let (key, pubkey) = make_keypair();
+        let (_, funded) = fund(&mut UTXOSet::new(), &pubkey, 100);
+        let tx = spend(&key, funded, &[(pubkey, 90)]);
+
+        let bytes = bincode::serialize(&tx).unwrap();
+        let json = bincode::deserialize::<Transaction>(&bytes)
+            .unwrap()
+            .to_json();
+        assert!(json.contains(&hex::encode(
+            pubkey.to_encoded_point(true).as_bytes())));
+
+        let decoded = Transaction::from_json(&json).unwrap();
+        assert_eq!(bincode::serialize(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn stolen_input_cannot_be_replayed() {
+        let (key, pubkey) = make_keypair();
+        let (_, thief) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+        let tx = spend(&key, funded, &[(pubkey, 90)]);
+        assert!(tx.is_valid(&utxo_set, 1, DEFAULT_CHAIN_ID).is_ok());
+
+        let mut replayed = Transaction::new();
+        replayed.add_input(tx.inputs()[0].clone());
+        replayed.add_output(Output::new()
+            .set_pubkey(thief)
+            .set_amount(90)
+            .collect());
+
+        assert!(matches!(replayed.is_valid(&utxo_set, 1, DEFAULT_CHAIN_ID),
+            Err(TransactionValidityError::InvalidSignature(0))));
+    }
+
+    #[test]
+    fn output_sum_near_u32_max_does_not_wrap() {
+        let (key, pubkey) = make_keypair();
+        let mut utxo_set = UTXOSet::new();
+        let (_, funded) = fund(&mut utxo_set, &pubkey, 100);
+
+        // Wrapped, the outputs would sum to 1 and fit under the input.
+        let tx = spend(&key, funded, &[(pubkey, u32::MAX), (pubkey, 2)]);
+        assert!(matches!(tx.is_valid(&utxo_set, 1, DEFAULT_CHAIN_ID),
+            Err(TransactionValidityError::AmountOverflow)));
+    }
+
+    #[test]
+    fn zero_target_without_coins_selects_nothing() {
+        let (_, pubkey) = make_keypair();
+        assert_eq!(select_coins(&UTXOSet::new(), &pubkey, 0, 1),
+            Some((Vec::new(), 0)));
+        assert_eq!(select_coins(&UTXOSet::new(), &pubkey, 1, 1), None);
+    }
+
+    #[test]
+    fn balances_saturate_instead_of_overflowing() {
+        let mut utxo_set = UTXOSet::new();
+        let (_, pubkey) = make_keypair();
+        fund(&mut utxo_set, &pubkey, u32::MAX);
+        fund(&mut utxo_set, &pubkey, 2);
+
+        assert_eq!(balance_of(&utxo_set, &pubkey), u32::MAX);
+        assert_eq!(spendable_balance(&utxo_set, &pubkey, 1), u32::MAX);
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf}
+};
+
+use hmac::{Hmac, Mac};
+use k256::{
+    ecdsa::{SigningKey, VerifyingKey},
+    sha2::Sha256
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::address::{pubkey_hash, PubkeyHash};
+use super::transaction::{
+    Input,
+    OutputLock,
+    PartialInput,
+    Sha256Hash,
+    UTXOSet
+};
+
+
+const DERIVATION_TAG: &[u8] = b"rustychain key";
+const WALLET_VERSION: u32 = 1;
+const KDF_ROUNDS: u32 = 100_000;
+const MAX_KDF_ROUNDS: u32 = 10_000_000;
+
+pub type Seed = [u8; 32];
+
+// On disk a wallet is the bincode of `WalletFile`. The secrets are encrypted
+// with an HMAC-SHA256 keystream under a key stretched from the passphrase
+// with PBKDF2-HMAC-SHA256, then authenticated with HMAC-SHA256 over every
+// other field (encrypt-then-MAC).
+#[derive(Deserialize, Serialize)]
+struct WalletFile {
+    version: u32,
+    kdf_rounds: u32,
+    salt: [u8; 16],
+    nonce: [u8; 16],
+    ciphertext: Vec<u8>,
+    mac: [u8; 32]
+}
+
+#[derive(Deserialize, Serialize)]
+struct WalletSecrets {
+    seed: Seed,
+    key_count: u32
+}
+
+pub struct Wallet {
+    path: PathBuf,
+    seed: Seed,
+    keys: Vec<SigningKey>,
+    kdf_rounds: u32,
+    salt: [u8; 16],
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32]
+}
+
+#[derive(Debug)]
+pub enum WalletError {
+    Io(io::Error),
+    InvalidFormat,
+    UnsupportedVersion(u32),
+    UnsupportedKdfRounds(u32),
+    AuthenticationFailed
+}
+
+impl Wallet {
+    pub fn create(path: &Path, passphrase: &str)
+            -> Result<Self, WalletError> {
+
+        if path.exists() {
+            return Err(WalletError::Io(ErrorKind::AlreadyExists.into()));
+        }
+
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let (cipher_key, mac_key) = stretch(passphrase, &salt, KDF_ROUNDS);
+
+        let seed = generate_seed();
+        let wallet = Wallet {
+            path: path.to_path_buf(),
+            seed,
+            keys: vec![derive_keypair(&seed, 0).0],
+            kdf_rounds: KDF_ROUNDS,
+            salt,
+            cipher_key,
+            mac_key
+        };
+        wallet.save().map_err(WalletError::Io)?;
+
+        Ok(wallet)
+    }
+
+    pub fn load(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        let bytes = fs::read(path).map_err(WalletError::Io)?;
+        let file: WalletFile = bincode::deserialize(&bytes)
+            .map_err(|_| WalletError::InvalidFormat)?;
+        if file.version != WALLET_VERSION {
+            return Err(WalletError::UnsupportedVersion(file.version));
+        }
+        // The round count is read before the MAC can be checked, so bound it
+        // or a tampered file could make loading spin practically forever.
+        if file.kdf_rounds == 0 || file.kdf_rounds > MAX_KDF_ROUNDS {
+            return Err(WalletError::UnsupportedKdfRounds(file.kdf_rounds));
+        }
+
+        let (cipher_key, mac_key) = stretch(passphrase, &file.salt,
+            file.kdf_rounds);
+        file_mac(&mac_key, &file)
+            .verify_slice(&file.mac)
+            .map_err(|_| WalletError::AuthenticationFailed)?;
+
+        let mut plaintext = file.ciphertext;
+        apply_keystream(&cipher_key, &file.nonce, &mut plaintext);
+        let secrets: WalletSecrets = bincode::deserialize(&plaintext)
+            .map_err(|_| WalletError::InvalidFormat)?;
+
+        Ok(Wallet {
+            path: path.to_path_buf(),
+            seed: secrets.seed,
+            keys: (0..secrets.key_count)
+                .map(|index| derive_keypair(&secrets.seed, index).0)
+                .collect(),
+            kdf_rounds: file.kdf_rounds,
+            salt: file.salt,
+            cipher_key,
+            mac_key
+        })
+    }
+
+    pub fn new_key(&mut self) -> io::Result<VerifyingKey> {
+        let (key, pubkey) = derive_keypair(&self.seed, self.keys.len() as u32);
+        self.keys.push(key);
+        self.save()?;
+
+        Ok(pubkey)
+    }
+
+    pub fn pubkeys(&self) -> Vec<VerifyingKey> {
+        self.keys.iter().map(|key| *key.verifying_key()).collect()
+    }
+
+    // One pass over the UTXO set. Pubkey-hash locks, by far the common case,
+    // are a set lookup; bare pubkey locks compare against the wallet's keys.
+    pub fn unspent(&self, utxo_set: &UTXOSet) -> Vec<(Sha256Hash, u32, u32)> {
+        let pubkeys = self.pubkeys();
+        let key_hashes = pubkeys
+            .iter()
+            .map(pubkey_hash)
+            .collect::<HashSet<PubkeyHash>>();
+
+        utxo_set
+            .iter()
+            .filter(|(_, entry)| match entry.output.lock() {
+                OutputLock::Pubkey(pubkey) => pubkeys.contains(pubkey),
+                OutputLock::PubkeyHash(hash) => key_hashes.contains(hash)
+            })
+            .map(|((tx_id, output_id), entry)|
+                (*tx_id, *output_id, entry.output.amount()))
+            .collect()
+    }
+
+    pub fn sign_input(&self, input: PartialInput, lock: &OutputLock,
+            sighash: &[u8]) -> Option<Input> {
+
+        match lock {
+            OutputLock::Pubkey(pubkey) => {
+                let key = self.keys
+                    .iter()
+                    .find(|key| key.verifying_key() == pubkey)?;
+                Some(input.sign(key, sighash))
+            }
+            OutputLock::PubkeyHash(hash) => {
+                let key = self.keys
+                    .iter()
+                    .find(|key| pubkey_hash(key.verifying_key()) == *hash)?;
+                Some(input.sign_with_pubkey(key, sighash))
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let secrets = WalletSecrets {
+            seed: self.seed,
+            key_count: self.keys.len() as u32
+        };
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let mut ciphertext = bincode::serialize(&secrets).unwrap();
+        apply_keystream(&self.cipher_key, &nonce, &mut ciphertext);
+
+        let mut file = WalletFile {
+            version: WALLET_VERSION,
+            kdf_rounds: self.kdf_rounds,
+            salt: self.salt,
+            nonce,
+            ciphertext,
+            mac: [0u8; 32]
+        };
+        file.mac = file_mac(&self.mac_key, &file)
+            .finalize()
+            .into_bytes()
+            .into();
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&bincode::serialize(&file).unwrap())?;
+        tmp_file.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+pub fn generate_seed() -> Seed {
+    let mut seed = [0u8; 32];
+    OsRng.fill_bytes(&mut seed);
+    seed
+}
+
+// Child key `index` is HMAC-SHA256(seed, tag || index || counter) read as a
+// secret scalar. The counter only moves past zero in the negligible case
+// where the digest is not a valid scalar.
+pub fn derive_keypair(seed: &Seed, index: u32) -> (SigningKey, VerifyingKey) {
+    for counter in 0u32.. {
+        let mut mac = hmac(seed);
+        mac.update(DERIVATION_TAG);
+        mac.update(&index.to_le_bytes());
+        mac.update(&counter.to_le_bytes());
+
+        if let Ok(key) = SigningKey::from_bytes(&mac.finalize().into_bytes()) {
+            let pubkey = *key.verifying_key();
+            return (key, pubkey);
+        }
+    }
+
+    unreachable!("no valid scalar derived")
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length")
+}
+
+// PBKDF2-HMAC-SHA256 with two output blocks: the cipher key and the MAC key.
+fn stretch(passphrase: &str, salt: &[u8], rounds: u32)
+        -> ([u8; 32], [u8; 32]) {
+
+    let block = |index: u32| {
+        let mut mac = hmac(passphrase.as_bytes());
+        mac.update(salt);
+        mac.update(&index.to_be_bytes());
+        let mut u: [u8; 32] = mac.finalize().into_bytes().into();
+
+        let mut out = u;
+        for _ in 1..rounds {
+            let mut mac = hmac(passphrase.as_bytes());
+            mac.update(&u);
+            u = mac.finalize().into_bytes().into();
+            out.iter_mut().zip(&u).for_each(|(out, u)| *out ^= u);
+        }
+        out
+    };
+
+    (block(1), block(2))
+}
+
+fn apply_keystream(key: &[u8; 32], nonce: &[u8; 16], data: &mut [u8]) {
+    for (counter, chunk) in data.chunks_mut(32).enumerate() {
+        let mut mac = hmac(key);
+        mac.update(nonce);
+        mac.update(&(counter as u64).to_le_bytes());
+        let keystream = mac.finalize().into_bytes();
+        chunk.iter_mut().zip(keystream).for_each(|(byte, key)| *byte ^= key);
+    }
+}
+
+fn file_mac(key: &[u8; 32], file: &WalletFile) -> Hmac<Sha256> {
+    let mut mac = hmac(key);
+    mac.update(&file.version.to_le_bytes());
+    mac.update(&file.kdf_rounds.to_le_bytes());
+    mac.update(&file.salt);
+    mac.update(&file.nonce);
+    mac.update(&file.ciphertext);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testutil::TempDir;
+
+    // Offsets into the bincode of `WalletFile`.
+    const KDF_ROUNDS_AT: usize = 4;
+    const CIPHERTEXT_AT: usize = 48;
+
+    // Stretching with the default round count is slow in debug builds, so
+    // most tests write a wallet with few rounds.
+    fn create_wallet(dir: &TempDir) -> (PathBuf, Wallet) {
+        let path = dir.path().join("wallet.dat");
+        let salt = [5u8; 16];
+        let (cipher_key, mac_key) = stretch("correct horse", &salt, 1000);
+        let mut wallet = Wallet {
+            path: path.clone(),
+            seed: generate_seed(),
+            keys: Vec::new(),
+            kdf_rounds: 1000,
+            salt,
+            cipher_key,
+            mac_key
+        };
+        wallet.new_key().unwrap();
+        wallet.new_key().unwrap();
+        (path, wallet)
+    }
+
+    fn set_kdf_rounds(path: &Path, rounds: u32) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[KDF_ROUNDS_AT..KDF_ROUNDS_AT + 4]
+            .copy_from_slice(&rounds.to_le_bytes());
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn stretch_matches_pbkdf2_hmac_sha256_vectors() {
+        // RFC 7914 section 11 and the SHA-256 counterparts of RFC 6070,
+        // each 64 bytes so both output blocks are covered.
+        let vectors = [
+            ("passwd", "salt", 1, "55ac046e56e3089fec1691c22544b605\
+                f94185216dde0465e68b9d57c20dacbc49ca9cccf179b645\
+                991664b39d77ef317c71b845b1e30bd509112041d3a19783"),
+            ("password", "salt", 2, "ae4d0c95af6b46d32d0adff928f06dd0\
+                2a303f8ef3c251dfd6e2d85a95474c43830651afcb5c862f\
+                0b249bd031f7a67520d136470f5ec271ece91c07773253d9"),
+            ("password", "salt", 4096, "c5e478d59288c841aa530db6845c4c8d\
+                962893a001ce4e11a4963873aa98134af7ad98c1b458ce3f\
+                d74ca35beba3cda7b8d1038d6a87071b918f837405f3fe77")
+        ];
+
+        for (passphrase, salt, rounds, expected) in vectors {
+            let (first, second) = stretch(passphrase, salt.as_bytes(),
+                rounds);
+            assert_eq!(hex::encode([first, second].concat()), expected);
+        }
+    }
+
+    #[test]
+    fn keystream_round_trips() {
+        let plaintext = (0..100u8).collect::<Vec<_>>();
+        let mut data = plaintext.clone();
+        apply_keystream(&[1u8; 32], &[2u8; 16], &mut data);
+        assert_ne!(data, plaintext);
+
+        let mut other_nonce = plaintext.clone();
+        apply_keystream(&[1u8; 32], &[3u8; 16], &mut other_nonce);
+        assert_ne!(other_nonce, data);
+
+        apply_keystream(&[1u8; 32], &[2u8; 16], &mut data);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn wallet_round_trips_through_the_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("wallet.dat");
+        let mut wallet = Wallet::create(&path, "correct horse").unwrap();
+        wallet.new_key().unwrap();
+
+        let loaded = Wallet::load(&path, "correct horse").unwrap();
+        assert_eq!(loaded.seed, wallet.seed);
+        assert_eq!(loaded.pubkeys(), wallet.pubkeys());
+        assert_eq!(loaded.pubkeys().len(), 2);
+    }
+
+    #[test]
+    fn wrong_passphrase_fails_authentication() {
+        let dir = TempDir::new();
+        let (path, _) = create_wallet(&dir);
+
+        assert!(matches!(Wallet::load(&path, "wrong horse"),
+            Err(WalletError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn flipped_bit_fails_authentication() {
+        let dir = TempDir::new();
+        let (path, _) = create_wallet(&dir);
+        let original = fs::read(&path).unwrap();
+
+        for at in [CIPHERTEXT_AT, original.len() - 1] {
+            let mut bytes = original.clone();
+            bytes[at] ^= 1;
+            fs::write(&path, bytes).unwrap();
+            assert!(matches!(Wallet::load(&path, "correct horse"),
+                Err(WalletError::AuthenticationFailed)));
+        }
+    }
+
+    #[test]
+    fn kdf_rounds_outside_the_limit_are_refused() {
+        let dir = TempDir::new();
+        let (path, _) = create_wallet(&dir);
+
+        for rounds in [0, MAX_KDF_ROUNDS + 1, u32::MAX] {
+            set_kdf_rounds(&path, rounds);
+            assert!(matches!(Wallet::load(&path, "correct horse"),
+                Err(WalletError::UnsupportedKdfRounds(found))
+                    if found == rounds));
+        }
+    }
+
+    #[test]
+    fn saved_wallet_keeps_its_kdf_rounds() {
+        let dir = TempDir::new();
+        let (path, wallet) = create_wallet(&dir);
+
+        let loaded = Wallet::load(&path, "correct horse").unwrap();
+        assert_eq!(loaded.kdf_rounds, 1000);
+        assert_eq!(loaded.pubkeys(), wallet.pubkeys());
+    }
+}
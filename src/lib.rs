@@ -0,0 +1,6 @@
+// The modules were private to the binary before the benches needed them,
+// and their `new` constructors were never paired with `Default`.
+#![allow(clippy::new_without_default)]
+
+pub mod blockchain;
+pub mod networking;
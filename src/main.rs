@@ -1,7 +1,3 @@
-mod blockchain;
-mod networking;
-
 fn main() {
     println!("Hello, world!");
 }
-
@@ -1,35 +1,384 @@
 use std::collections::{HashMap, VecDeque, HashSet};
 use std::io::{Result, Error, ErrorKind, Write, Read};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs, IpAddr};
-use std::sync::Mutex;
-use std::thread;
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+    Shutdown,
+    SocketAddr,
+    TcpListener,
+    TcpStream,
+    ToSocketAddrs
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{
+    mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    Arc,
+    Mutex
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use k256::ecdsa::VerifyingKey;
+use log::{debug, info, warn};
+use rand_core::{OsRng, RngCore};
+
+use crate::blockchain::block::{Block, BlockHeader, BlockValidityError};
+use crate::blockchain::bloom::BloomFilter;
+use crate::blockchain::global_state::{GlobalState, StateWithFile};
+use crate::blockchain::mempool::Mempool;
+use crate::blockchain::transaction::{
+    balance_of,
+    short_hash,
+    short_tx_id,
+    Sha256Hash,
+    Transaction
+};
+use crate::networking::message::{
+    MessageHeader,
+    MessageType,
+    MAX_HEADERS_PER_MESSAGE,
+    MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION
+};
+
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const BALANCE_QUERY_WINDOW: Duration = Duration::from_secs(60);
+const MAX_BALANCE_QUERIES: u32 = 30;
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+const BAN_THRESHOLD: u32 = 100;
+const INVALID_MESSAGE_SCORE: u32 = 20;
+const INVALID_BLOCK_SCORE: u32 = 100;
+const MAX_KNOWN_PEERS: usize = 1000;
+const MAX_PARTIAL_BLOCKS: usize = 4;
+const PARTIAL_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+// A peer is the remote address together with the port it listens on, as
+// announced in its handshake.
+type PeerKey = (IpAddr, u16);
+type KnownPeers = HashMap<SocketAddr, SystemTime>;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerDirection {
+    Inbound,
+    Outbound
+}
+
+#[derive(Debug)]
+pub enum PeerEvent {
+    Connected(SocketAddr),
+    Disconnected(SocketAddr),
+    Message(SocketAddr, MessageHeader)
+}
+
+
+struct PartialBlock {
+    header: BlockHeader,
+    txs: Vec<Option<Transaction>>
+}
+
+impl PartialBlock {
+    fn new(header: BlockHeader, short_tx_ids: &[u64], mempool: &Mempool)
+            -> Self {
+
+        let known_txs = mempool
+            .iter()
+            .map(|tx| (short_tx_id(&tx.calculate_id()), tx))
+            .collect::<HashMap<_, _>>();
+        let txs = short_tx_ids
+            .iter()
+            .map(|short_id| known_txs.get(short_id).map(|tx| (*tx).clone()))
+            .collect();
+
+        PartialBlock { header, txs }
+    }
+
+    fn missing(&self) -> Vec<u32> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    fn fill(&mut self, txs: Vec<Transaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != txs.len() {
+            return false;
+        }
+
+        for (i, tx) in missing.into_iter().zip(txs) {
+            self.txs[i as usize] = Some(tx);
+        }
+        true
+    }
+
+    fn into_block(self) -> Option<Block> {
+        let txs = self.txs.into_iter().collect::<Option<Vec<_>>>()?;
+        Block::from_parts(self.header, txs).ok()
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 8
+        }
+    }
+}
 
-use crate::networking::message::{MessageHeader, MessageType};
 
 
 pub struct NetworkInterface {
-    peers: Mutex<Vec<TcpStream>>
+    port: u16,
+    state: Arc<GlobalState>,
+    peers: Mutex<HashMap<PeerKey, TcpStream>>,
+    peer_directions: Mutex<HashMap<PeerKey, PeerDirection>>,
+    max_inbound: usize,
+    max_outbound: usize,
+    // Sent in every handshake, so a node that dials itself recognises its
+    // own nonce on the accepting side.
+    nonce: u64,
+    requested_txs: Mutex<HashMap<Sha256Hash, Instant>>,
+    balance_queries: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    ban_scores: Mutex<HashMap<IpAddr, u32>>,
+    latencies: Mutex<HashMap<PeerKey, Duration>>,
+    filters: Mutex<HashMap<PeerKey, BloomFilter>>,
+    banned: Mutex<HashMap<IpAddr, Instant>>,
+    known_peers: Option<Mutex<StateWithFile<KnownPeers>>>,
+    peer_threads: Mutex<Vec<JoinHandle<Result<()>>>>,
+    shutdown_flag: Arc<AtomicBool>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    reconnect_policy: ReconnectPolicy,
+    ban_duration: Duration,
+    events: Sender<PeerEvent>
 }
 
 impl NetworkInterface {
-    pub fn new() -> Self {
+    pub fn new(port: u16, state: Arc<GlobalState>, max_inbound: usize,
+            max_outbound: usize, events: Sender<PeerEvent>) -> Self {
+
+        // Without a readable peers file the node still runs, it just has to
+        // bootstrap from scratch on every start.
+        let peers_path = state.data_dir().join("peers");
+        let known_peers = match StateWithFile::new(&peers_path,
+                KnownPeers::new()) {
+            Ok(known_peers) => Some(Mutex::new(known_peers)),
+            Err(err) => {
+                warn!("[PEER STORE UNAVAILABLE][{:?}]", err);
+                None
+            }
+        };
+
         NetworkInterface {
-            peers: Mutex::new(Vec::new())
+            port,
+            state,
+            peers: Mutex::new(HashMap::new()),
+            peer_directions: Mutex::new(HashMap::new()),
+            max_inbound,
+            max_outbound,
+            nonce: OsRng.next_u64(),
+            requested_txs: Mutex::new(HashMap::new()),
+            balance_queries: Mutex::new(HashMap::new()),
+            ban_scores: Mutex::new(HashMap::new()),
+            latencies: Mutex::new(HashMap::new()),
+            filters: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashMap::new()),
+            known_peers,
+            peer_threads: Mutex::new(Vec::new()),
+            shutdown_flag: Arc::new(AtomicBool::new(false)),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            reconnect_policy: ReconnectPolicy::new(),
+            ban_duration: DEFAULT_BAN_DURATION,
+            events
+        }
+    }
+
+    pub fn set_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    pub fn set_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    pub fn reconnect_policy(&self) -> ReconnectPolicy {
+        self.reconnect_policy
+    }
+
+    pub fn set_ban_duration(mut self, duration: Duration) -> Self {
+        self.ban_duration = duration;
+        self
+    }
+
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let (relay, relayed_txs) = mpsc::channel();
+        self.state.set_tx_relay(relay);
+
+        let interface = Arc::clone(self);
+        let relay_handle = thread::spawn(move ||
+            interface.relay_transactions(relayed_txs));
+
+        let interface = Arc::clone(self);
+        thread::spawn(move || {
+            interface.listen_for_connections();
+            let _ = relay_handle.join();
+
+            let peer_threads = interface.peer_threads
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<_>>();
+            for handle in peer_threads {
+                let _ = handle.join();
+            }
+        })
+    }
+
+    pub fn shutdown(&self) {
+        info!("[SHUTDOWN]");
+        self.shutdown_flag.store(true, Ordering::SeqCst);
+
+        for peer in self.peers.lock().unwrap().values() {
+            let _ = peer.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn max_inbound(&self) -> usize {
+        self.max_inbound
+    }
+
+    pub fn max_outbound(&self) -> usize {
+        self.max_outbound
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn peer_latencies(&self) -> HashMap<SocketAddr, Duration> {
+        self.latencies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, latency)| (SocketAddr::from(*key), *latency))
+            .collect()
+    }
+
+    fn count_peers(&self, direction: PeerDirection) -> usize {
+        self.peer_directions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer_direction| **peer_direction == direction)
+            .count()
+    }
+
+    fn has_free_slot(&self, direction: PeerDirection) -> bool {
+        let max = match direction {
+            PeerDirection::Inbound => self.max_inbound,
+            PeerDirection::Outbound => self.max_outbound
+        };
+
+        self.count_peers(direction) < max
+    }
+
+    pub fn connect_to_peer(self: &Arc<Self>, address: SocketAddr)
+            -> Result<()> {
+
+        let key = peer_key(address);
+        if self.is_peer(key) {
+            return Ok(());
+        }
+
+        if self.is_banned(address.ip()) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Peer is banned"
+            ));
+        }
+
+        if !self.has_free_slot(PeerDirection::Outbound) {
+            return Err(Error::other("No free outbound peer slots"));
+        }
+
+        let conn = self.handshake(address)?;
+        if !self.add_peer(conn, key, PeerDirection::Outbound) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Node is already a peer"
+            ));
         }
+
+        Ok(())
     }
 
-    pub fn connect_to_peer(&self, ip: IpAddr) -> Result<()> {
-        let mut conn = TcpStream::connect(format!("{ip}:1234"))?;
+    fn handshake(&self, address: SocketAddr) -> Result<TcpStream> {
+        let mut conn = self.open_connection(address)?;
+        let (tip, utxo_commitment) = self.state.utxo_commitment();
 
         MessageHeader::new()
-            .set_type(MessageType::StartPeering)
+            .set_type(MessageType::StartPeering {
+                protocol_version: PROTOCOL_VERSION,
+                genesis_hash: self.state.genesis_hash(),
+                chain_id: self.state.chain_id(),
+                tip,
+                utxo_commitment,
+                listen_port: self.port,
+                nonce: self.nonce
+            })
             .send_to(&mut conn)?;
 
         let res = MessageHeader::receive_from(&mut conn)?;
 
         if res.is_ack() {
-            self.add_peer(conn);
-            return Ok(());
+            return Ok(conn);
+        }
+
+        if let MessageType::Reject { reason } = res.message_type {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Node rejected peering: {reason}")
+            ));
         }
 
         Err(Error::new(
@@ -38,8 +387,10 @@ impl NetworkInterface {
         ))
     }
 
-    pub fn ask_for_peers(&self, ip: IpAddr) -> Result<Vec<IpAddr>> {
-        let mut conn = TcpStream::connect(format!("{ip}:1234"))?;
+    pub fn ask_for_peers(&self, address: SocketAddr)
+            -> Result<Vec<SocketAddr>> {
+
+        let mut conn = self.open_connection(address)?;
 
         MessageHeader::new()
             .set_type(MessageType::ListPeers)
@@ -58,108 +409,885 @@ impl NetworkInterface {
         conn.read_exact(&mut num_peers)?;
         let num_peers = num_peers[0];
 
-        let mut peers = Vec::<IpAddr>::new();
+        let mut peers = Vec::<SocketAddr>::new();
         for _ in 0..num_peers {
             let mut ip_ver = [0u8];
             conn.read_exact(&mut ip_ver)?;
             let ip_ver = ip_ver[0];
 
-            if ip_ver == 4 {
-                let mut ip = [0u8; 4];
-                conn.read_exact(&mut ip)?;
+            let ip_addr = match ip_ver {
+                4 => {
+                    let mut ip = [0u8; 4];
+                    conn.read_exact(&mut ip)?;
+                    IpAddr::from(ip)
+                }
+                6 => {
+                    let mut ip = [0u8; 16];
+                    conn.read_exact(&mut ip)?;
+                    IpAddr::from(ip)
+                }
+                _ => return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Node sent an unknown address type"
+                ))
+            };
 
-                let ip_addr = IpAddr::from(ip);
-                peers.push(ip_addr);
-            }
+            let mut port = [0u8; 2];
+            conn.read_exact(&mut port)?;
+            peers.push(SocketAddr::new(ip_addr, u16::from_le_bytes(port)));
+        }
+
+        Ok(peers)
+    }
 
-            if ip_ver == 6 {
-                let mut ip = [0u8; 16];
-                conn.read_exact(&mut ip)?;
+    pub fn request_headers(&self, address: SocketAddr, from_height: u32)
+            -> Result<Vec<BlockHeader>> {
 
-                let ip_addr = IpAddr::from(ip);
-                peers.push(ip_addr);
+        let mut conn = self.open_connection(address)?;
+
+        MessageHeader::new()
+            .set_type(MessageType::GetHeaders { from_height })
+            .send_to(&mut conn)?;
+
+        let res = MessageHeader::receive_from(&mut conn)?;
+
+        match res.message_type {
+            MessageType::Headers(headers) => Ok(headers),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Node did not send headers"
+            ))
+        }
+    }
+
+    pub fn request_block(&self, address: SocketAddr, hash: &Sha256Hash)
+            -> Result<Option<Block>> {
+
+        let mut conn = self.open_connection(address)?;
+
+        MessageHeader::new()
+            .set_type(MessageType::GetBlock(*hash))
+            .send_to(&mut conn)?;
+
+        let res = MessageHeader::receive_from(&mut conn)?;
+
+        match res.message_type {
+            MessageType::BlockResponse(block) => Ok(block),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Node did not send a block response"
+            ))
+        }
+    }
+
+    pub fn request_balance(&self, address: SocketAddr, pubkey: &VerifyingKey)
+            -> Result<u32> {
+
+        let mut conn = self.open_connection(address)?;
+
+        MessageHeader::new()
+            .set_type(MessageType::GetBalance(*pubkey))
+            .send_to(&mut conn)?;
+
+        let res = MessageHeader::receive_from(&mut conn)?;
+
+        match res.message_type {
+            MessageType::Balance(balance) => Ok(balance),
+            MessageType::Reject { reason } => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Node rejected balance query: {reason}")
+            )),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Node did not send a balance"
+            ))
+        }
+    }
+
+    pub fn sync_headers(&self, address: SocketAddr, from_height: u32,
+            previous_block: Sha256Hash) -> Result<Vec<BlockHeader>> {
+
+        debug!("[SYNC HEADERS][{}][{}]", address, from_height);
+
+        let mut headers = Vec::<BlockHeader>::new();
+        let mut parent = previous_block;
+        loop {
+            let page = self.request_headers(address,
+                from_height + headers.len() as u32)?;
+            let page_len = page.len() as u32;
+
+            for header in page {
+                if header.previous_block != parent
+                        || !header.is_valid_pow(header.difficulty) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Node sent an invalid header chain"
+                    ));
+                }
+
+                parent = header.hash();
+                headers.push(header);
+            }
+
+            if page_len < MAX_HEADERS_PER_MESSAGE {
+                break;
             }
         }
 
-        Ok(peers)
+        Ok(headers)
+    }
+
+    fn open_connection(&self, address: SocketAddr) -> Result<TcpStream> {
+        let conn = TcpStream::connect_timeout(&address, self.connect_timeout)?;
+        self.set_timeouts(&conn)?;
+        Ok(conn)
+    }
+
+    fn set_timeouts(&self, conn: &TcpStream) -> Result<()> {
+        conn.set_read_timeout(Some(self.read_timeout))?;
+        conn.set_write_timeout(Some(self.write_timeout))
     }
 
-    pub fn listen_for_connections(&self) {
-        let listener = TcpListener::bind("0.0.0.0:1234").unwrap();
-        for conn in listener.incoming() {
-            let mut conn = {
-                match conn {
-                    Ok(val) => val,
-                    Err(_) => continue
+    pub fn listen_for_connections(self: &Arc<Self>) {
+        let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, self.port))
+            .or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.port)))
+            .unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        while !self.shutdown_flag.load(Ordering::SeqCst) {
+            let mut conn = match listener.accept() {
+                Ok((val, _)) => val,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
                 }
+                Err(_) => continue
+            };
+
+            let ip = match conn.peer_addr() {
+                Ok(addr) => addr.ip(),
+                Err(_) => continue
             };
 
+            if self.is_banned(ip) {
+                debug!("[REFUSED BANNED PEER][{}]", ip);
+                let _ = conn.shutdown(Shutdown::Both);
+                continue;
+            }
+
+            if conn.set_nonblocking(false).is_err()
+                    || self.set_timeouts(&conn).is_err() {
+                continue;
+            }
+
             let message = match MessageHeader::receive_from(&mut conn) {
                 Ok(val) => val,
-                Err(_) => continue
+                Err(err) => {
+                    if err.kind() == ErrorKind::InvalidData {
+                        self.misbehaving(ip, INVALID_MESSAGE_SCORE);
+                    }
+                    continue;
+                }
             };
 
-            if let MessageType::StartPeering = message.message_type {
-                if self.peers.lock().unwrap().len() == 6 {
-                    let _ = MessageHeader::new()
-                        .set_type(MessageType::Nack)
+            match message.message_type {
+                MessageType::StartPeering {
+                    protocol_version,
+                    genesis_hash,
+                    chain_id,
+                    tip,
+                    utxo_commitment,
+                    listen_port,
+                    nonce
+                } => {
+                    if nonce == self.nonce {
+                        debug!("[REFUSED SELF CONNECTION]");
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Nack)
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    if protocol_version < MIN_PROTOCOL_VERSION
+                            || protocol_version > PROTOCOL_VERSION {
+                        warn!("[REJECTED PEER][VERSION {}]",
+                            protocol_version);
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Reject {
+                                reason: format!(
+                                    "Unsupported protocol version {}, \
+                                    expected {}..={}",
+                                    protocol_version,
+                                    MIN_PROTOCOL_VERSION,
+                                    PROTOCOL_VERSION
+                                )
+                            })
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    if genesis_hash != self.state.genesis_hash() {
+                        warn!("[REJECTED PEER][GENESIS {}]",
+                            short_hash(&genesis_hash));
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Reject {
+                                reason: format!("Unknown genesis block {}",
+                                    short_hash(&genesis_hash))
+                            })
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    if chain_id != self.state.chain_id() {
+                        warn!("[REJECTED PEER][CHAIN ID {}]", chain_id);
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Reject {
+                                reason: format!("Wrong chain id {}, \
+                                    expected {}",
+                                    chain_id,
+                                    self.state.chain_id()
+                                )
+                            })
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    // Peers at the same tip must hold the same UTXO set.
+                    let (own_tip, own_commitment) =
+                        self.state.utxo_commitment();
+                    if tip == own_tip && utxo_commitment != own_commitment {
+                        warn!("[REJECTED PEER][UTXO DIVERGENCE {}]",
+                            short_hash(&tip));
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Reject {
+                                reason: format!("UTXO set diverges at {}",
+                                    short_hash(&tip))
+                            })
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    if !self.has_free_slot(PeerDirection::Inbound) {
+                        warn!("[REJECTED PEER][INBOUND FULL]");
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Reject {
+                                reason: "No free inbound peer slots".to_owned()
+                            })
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    let key = peer_key(SocketAddr::new(ip, listen_port));
+                    if self.is_peer(key) {
+                        let _ = MessageHeader::new()
+                            .set_type(MessageType::Nack)
+                            .send_to(&mut conn);
+                        continue;
+                    }
+
+                    let res = MessageHeader::new()
+                        .set_type(MessageType::Ack)
                         .send_to(&mut conn);
-                    continue;
+
+                    if let Err(_) = res {
+                        continue;
+                    }
+
+                    self.add_peer(conn.try_clone().unwrap(), key,
+                        PeerDirection::Inbound);
                 }
 
-                let res = MessageHeader::new()
-                    .set_type(MessageType::Ack)
-                    .send_to(&mut conn);
+                MessageType::ListPeers => {
+                    let res = MessageHeader::new()
+                        .set_type(MessageType::Ack)
+                        .send_to(&mut conn);
 
-                if let Err(_) = res {
-                    continue;
+                    if let Err(_) = res {
+                        continue;
+                    }
+
+                    if let Err(_) = self.list_peers(&mut conn) {
+                        continue;
+                    }
+                }
+
+                MessageType::GetHeaders { from_height } => {
+                    let _ = send_headers(&mut conn, &self.state, from_height);
                 }
 
-                self.add_peer(conn.try_clone().unwrap());
+                MessageType::GetBlock(hash) => {
+                    let _ = send_block(&mut conn, &self.state, &hash);
+                }
+
+                MessageType::GetBalance(pubkey) => {
+                    let _ = self.send_balance(&mut conn, &pubkey);
+                }
+
+                _ => {
+                    debug!("[IGNORED MESSAGE][{}]", ip);
+                }
             }
+        }
+    }
 
-            if let MessageType::ListPeers = message.message_type {
-                let res = MessageHeader::new()
-                    .set_type(MessageType::Ack)
-                    .send_to(&mut conn);
+    fn is_peer(&self, key: PeerKey) -> bool {
+        self.peers.lock().unwrap().contains_key(&key)
+    }
 
-                if let Err(_) = res {
-                    continue;
+    fn add_peer(self: &Arc<Self>, conn: TcpStream, key: PeerKey,
+            direction: PeerDirection) -> bool {
+
+        let address = SocketAddr::from(key);
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if peers.contains_key(&key) {
+                debug!("[SKIPPED PEER][{}]", address);
+                let _ = conn.shutdown(Shutdown::Both);
+                return false;
+            }
+            peers.insert(key, conn.try_clone().unwrap());
+        }
+
+        info!("[ADDED PEER][{}]", address);
+        self.peer_directions.lock().unwrap().insert(key, direction);
+        self.state.metrics().set_peer_count(self.peer_count());
+        self.mark_seen(key);
+        let _ = self.events.send(PeerEvent::Connected(address));
+
+        let interface = Arc::clone(self);
+        let handle = thread::spawn(move || interface.serve_peer(key, conn));
+        self.peer_threads.lock().unwrap().push(handle);
+        true
+    }
+
+    fn serve_peer(&self, key: PeerKey, conn: TcpStream) -> Result<()> {
+        let address = SocketAddr::from(key);
+        let mut conn = conn;
+        loop {
+            let res = self.listen_to_messages(key, conn);
+            if let Err(err) = &res {
+                debug!("[PEER ERROR][{}][{}]", address, err);
+            }
+
+            self.peers.lock().unwrap().remove(&key);
+            self.peer_directions.lock().unwrap().remove(&key);
+            self.latencies.lock().unwrap().remove(&key);
+            self.filters.lock().unwrap().remove(&key);
+            self.state.metrics().set_peer_count(self.peer_count());
+            self.mark_seen(key);
+            info!("[DISCONNECTED PEER][{}]", address);
+            let _ = self.events.send(PeerEvent::Disconnected(address));
+
+            conn = match self.reconnect(key) {
+                Some(conn) => conn,
+                None => return res
+            };
+
+            info!("[RECONNECTED PEER][{}]", address);
+            self.peers.lock().unwrap().insert(key, conn.try_clone()?);
+            self.peer_directions
+                .lock()
+                .unwrap()
+                .insert(key, PeerDirection::Outbound);
+            self.state.metrics().set_peer_count(self.peer_count());
+            self.mark_seen(key);
+            let _ = self.events.send(PeerEvent::Connected(address));
+        }
+    }
+
+    fn listen_to_messages(&self, key: PeerKey, conn: TcpStream)
+            -> Result<()> {
+
+        let mut conn = conn;
+        let (ip, _) = key;
+        let mut pending_ping = None;
+        let mut last_ping = Instant::now();
+        let mut partial_blocks =
+            HashMap::<Sha256Hash, (PartialBlock, Instant)>::new();
+
+        while !self.shutdown_flag.load(Ordering::SeqCst) {
+            let res = self.keep_alive(&mut conn, &mut pending_ping,
+                &mut last_ping);
+            if let Err(err) = res {
+                warn!("[PING TIMEOUT][{}]", ip);
+                let _ = conn.shutdown(Shutdown::Both);
+                return Err(err);
+            }
+
+            let message = match MessageHeader::receive_from(&mut conn) {
+                Ok(val) => val,
+                Err(err) if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::TimedOut => continue,
+                Err(err) => {
+                    if err.kind() == ErrorKind::InvalidData {
+                        self.misbehaving(ip, INVALID_MESSAGE_SCORE);
+                    }
+                    let _ = conn.shutdown(Shutdown::Both);
+                    return Err(err);
                 }
+            };
 
-                if let Err(_) = self.list_peers(&mut conn) {
-                    continue;
+            let _ = self.events.send(PeerEvent::Message(SocketAddr::from(key),
+                message.clone()));
+
+            match message.message_type {
+                MessageType::NewBlock(block) => {
+                    self.receive_block(ip, block);
                 }
+
+                MessageType::BlockResponse(Some(block)) => {
+                    self.receive_block(ip, block);
+                }
+
+                MessageType::CompactBlock { header, short_tx_ids } => {
+                    let block_hash = header.hash();
+                    debug!("[RECEIVED COMPACT BLOCK][{}][{}]", ip,
+                        short_hash(&block_hash));
+                    if self.state.block_offset(&block_hash).is_some() {
+                        continue;
+                    }
+
+                    // Reconstruction scans the mempool and waits on the
+                    // peer, so only headers carrying the required work get
+                    // that far. Children of unknown blocks are fetched whole
+                    // and left to the orphan pool.
+                    let required = self.state
+                        .child_difficulty(&header.previous_block);
+                    let difficulty = match required {
+                        Some(difficulty) => difficulty,
+                        None => {
+                            MessageHeader::new()
+                                .set_type(MessageType::GetBlock(block_hash))
+                                .send_to(&mut conn)?;
+                            continue;
+                        }
+                    };
+                    if header.difficulty != difficulty {
+                        let err = BlockValidityError::WrongDifficulty;
+                        self.misbehaving(ip, block_ban_score(&err));
+                        continue;
+                    }
+                    if !header.is_valid_pow(difficulty) {
+                        let err = BlockValidityError::InvalidHash;
+                        self.misbehaving(ip, block_ban_score(&err));
+                        continue;
+                    }
+
+                    let partial = PartialBlock::new(header, &short_tx_ids,
+                        &self.state.mempool.lock().unwrap());
+                    let indexes = partial.missing();
+                    if indexes.is_empty() {
+                        self.complete_block(&mut conn, ip, partial)?;
+                        continue;
+                    }
+
+                    MessageHeader::new()
+                        .set_type(MessageType::GetBlockTxs {
+                            block_hash,
+                            indexes
+                        })
+                        .send_to(&mut conn)?;
+
+                    let now = Instant::now();
+                    partial_blocks.retain(|_, (_, received)|
+                        now.duration_since(*received) < PARTIAL_BLOCK_TIMEOUT);
+                    if partial_blocks.len() >= MAX_PARTIAL_BLOCKS {
+                        let oldest = partial_blocks
+                            .iter()
+                            .min_by_key(|(_, (_, received))| *received)
+                            .map(|(hash, _)| *hash)
+                            .unwrap();
+                        partial_blocks.remove(&oldest);
+                    }
+                    partial_blocks.insert(block_hash, (partial, now));
+                }
+
+                MessageType::GetBlockTxs { block_hash, indexes } => {
+                    send_block_txs(&mut conn, &self.state, &block_hash,
+                        &indexes)?;
+                }
+
+                MessageType::BlockTxs { block_hash, txs } => {
+                    let mut partial = match partial_blocks.remove(&block_hash) {
+                        Some((partial, received)) if received.elapsed()
+                                < PARTIAL_BLOCK_TIMEOUT => partial,
+                        _ => continue
+                    };
+
+                    if partial.fill(txs) {
+                        self.complete_block(&mut conn, ip, partial)?;
+                    } else {
+                        MessageHeader::new()
+                            .set_type(MessageType::GetBlock(block_hash))
+                            .send_to(&mut conn)?;
+                    }
+                }
+
+                MessageType::GetHeaders { from_height } => {
+                    send_headers(&mut conn, &self.state, from_height)?;
+                }
+
+                MessageType::GetBlock(hash) => {
+                    send_block(&mut conn, &self.state, &hash)?;
+                }
+
+                MessageType::TxInv(tx_ids) => {
+                    for tx_id in self.request_missing_txs(&tx_ids) {
+                        MessageHeader::new()
+                            .set_type(MessageType::GetTx(tx_id))
+                            .send_to(&mut conn)?;
+                    }
+                }
+
+                MessageType::GetTx(tx_id) => {
+                    send_transaction(&mut conn, &self.state, &tx_id)?;
+                }
+
+                MessageType::TxResponse(tx) => {
+                    self.receive_transaction(tx);
+                }
+
+                MessageType::GetBalance(pubkey) => {
+                    self.send_balance(&mut conn, &pubkey)?;
+                }
+
+                MessageType::SetFilter(filter) => {
+                    if !filter.is_valid() {
+                        self.misbehaving(ip, INVALID_MESSAGE_SCORE);
+                        continue;
+                    }
+
+                    debug!("[SET FILTER][{}]", ip);
+                    self.filters.lock().unwrap().insert(key, filter);
+                }
+
+                MessageType::Ping(nonce) => {
+                    MessageHeader::new()
+                        .set_type(MessageType::Pong(nonce))
+                        .send_to(&mut conn)?;
+                }
+
+                MessageType::Pong(nonce) => {
+                    if let Some((expected, sent)) = pending_ping {
+                        if nonce == expected {
+                            self.latencies
+                                .lock()
+                                .unwrap()
+                                .insert(key, sent.elapsed());
+                            pending_ping = None;
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        let _ = conn.shutdown(Shutdown::Both);
+        Ok(())
+    }
+
+    fn receive_block(&self, ip: IpAddr, block: Block) {
+        let hash = short_hash(&block.hash());
+        debug!("[RECEIVED BLOCK][{}][{}]", ip, hash);
+        self.state.metrics().record_received_block();
+        if let Err(err) = self.state.accept_block(block) {
+            warn!("[REJECTED BLOCK][{}][{:?}]", hash, err);
+            self.misbehaving(ip, block_ban_score(&err));
+        }
+    }
+
+    fn complete_block(&self, conn: &mut TcpStream, ip: IpAddr,
+            partial: PartialBlock) -> Result<()> {
+
+        let block_hash = partial.header.hash();
+        match partial.into_block() {
+            Some(block) => {
+                self.receive_block(ip, block);
+                Ok(())
+            }
+            None => {
+                debug!("[COMPACT BLOCK FALLBACK][{}]", short_hash(&block_hash));
+                MessageHeader::new()
+                    .set_type(MessageType::GetBlock(block_hash))
+                    .send_to(conn)
+            }
+        }
+    }
+
+    fn keep_alive(&self, conn: &mut TcpStream,
+            pending_ping: &mut Option<(u64, Instant)>,
+            last_ping: &mut Instant) -> Result<()> {
+
+        if let Some((_, sent)) = pending_ping {
+            if sent.elapsed() > PING_TIMEOUT {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Peer did not answer ping"
+                ));
+            }
+
+            return Ok(());
+        }
+
+        if last_ping.elapsed() >= PING_INTERVAL {
+            let nonce = OsRng.next_u64();
+            MessageHeader::new()
+                .set_type(MessageType::Ping(nonce))
+                .send_to(conn)?;
+
+            *last_ping = Instant::now();
+            *pending_ping = Some((nonce, *last_ping));
+        }
+
+        Ok(())
+    }
+
+    fn reconnect(&self, key: PeerKey) -> Option<TcpStream> {
+        let address = SocketAddr::from(key);
+        let policy = self.reconnect_policy;
+        let mut delay = policy.initial_delay;
+
+        for attempt in 1..=policy.max_attempts {
+            if !self.sleep_unless_shutdown(delay)
+                    || self.is_peer(key)
+                    || self.is_banned(address.ip())
+                    || !self.has_free_slot(PeerDirection::Outbound) {
+                return None;
+            }
+
+            debug!("[RECONNECT][{}][{}/{}]", address, attempt,
+                policy.max_attempts);
+            if let Ok(conn) = self.handshake(address) {
+                return Some(conn);
+            }
+
+            delay = (delay * 2).min(policy.max_delay);
+        }
+
+        warn!("[GAVE UP RECONNECTING][{}]", address);
+        None
+    }
+
+    fn sleep_unless_shutdown(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.shutdown_flag.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+
+            thread::sleep((deadline - now).min(Duration::from_millis(100)));
+        }
+
+        false
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        let until = Instant::now() + self.ban_duration;
+        self.banned.lock().unwrap().insert(ip, until);
+        self.ban_scores.lock().unwrap().remove(&ip);
+        warn!("[BANNED PEER][{}]", ip);
+
+        for ((peer_ip, _), conn) in self.peers.lock().unwrap().iter() {
+            if *peer_ip == ip {
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        self.banned.lock().unwrap().remove(&ip);
+        self.ban_scores.lock().unwrap().remove(&ip);
+        info!("[UNBANNED PEER][{}]", ip);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut banned = self.banned.lock().unwrap();
+        match banned.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                banned.remove(&ip);
+                false
+            }
+            None => false
+        }
+    }
+
+    fn misbehaving(&self, ip: IpAddr, score: u32) {
+        if score == 0 {
+            return;
+        }
+
+        let ip = ip.to_canonical();
+        let total = {
+            let mut ban_scores = self.ban_scores.lock().unwrap();
+            let total = ban_scores.entry(ip).or_insert(0);
+            *total = total.saturating_add(score);
+            *total
+        };
+        warn!("[MISBEHAVING PEER][{}][{}]", ip, total);
+
+        if total >= BAN_THRESHOLD {
+            self.ban(ip);
+        }
+    }
+
+    pub fn broadcast_block(&self, block: &Block) {
+        debug!("[BROADCAST BLOCK][{}]", short_hash(&block.hash()));
+
+        let compact_block = MessageHeader::new()
+            .set_type(MessageType::CompactBlock {
+                header: block.header.clone(),
+                short_tx_ids: block.short_tx_ids()
+            });
+
+        let mut peers = self.peers.lock().unwrap();
+        for peer in peers.values_mut() {
+            let _ = compact_block.send_to(peer);
+        }
+    }
+
+    // Peers that registered a Bloom filter only hear about the transactions
+    // matching it.
+    pub fn broadcast_tx_inv(&self, tx_ids: &[Sha256Hash]) {
+        debug!("[BROADCAST TX INV][{}]", tx_ids.len());
+
+        let txs = {
+            let mempool = self.state.mempool.lock().unwrap();
+            tx_ids
+                .iter()
+                .filter_map(|tx_id| mempool.get(tx_id).cloned())
+                .collect::<Vec<_>>()
+        };
+
+        let mut filters = self.filters.lock().unwrap();
+        let mut peers = self.peers.lock().unwrap();
+        for (key, peer) in peers.iter_mut() {
+            let tx_ids = match filters.get_mut(key) {
+                Some(filter) => txs
+                    .iter()
+                    .filter(|tx| filter.matches_transaction(tx))
+                    .map(|tx| tx.calculate_id())
+                    .collect(),
+                None => tx_ids.to_vec()
+            };
+
+            if tx_ids.is_empty() {
+                continue;
+            }
+
+            let _ = MessageHeader::new()
+                .set_type(MessageType::TxInv(tx_ids))
+                .send_to(peer);
+        }
+    }
+
+    pub fn send_filter(&self, address: SocketAddr, filter: &BloomFilter)
+            -> Result<()> {
+
+        let mut peers = self.peers.lock().unwrap();
+        let conn = peers.get_mut(&peer_key(address)).ok_or_else(||
+            Error::new(ErrorKind::NotConnected, "Not peered with node"))?;
+
+        MessageHeader::new()
+            .set_type(MessageType::SetFilter(filter.clone()))
+            .send_to(conn)
+    }
+
+    fn send_balance(&self, conn: &mut TcpStream, pubkey: &VerifyingKey)
+            -> Result<()> {
+
+        let ip = conn.peer_addr()?.ip();
+        debug!("[GET BALANCE][{}]", conn.peer_addr()?);
+
+        if !self.allow_balance_query(ip) {
+            return MessageHeader::new()
+                .set_type(MessageType::Reject {
+                    reason: "Too many balance queries".to_owned()
+                })
+                .send_to(conn);
+        }
+
+        let balance = balance_of(&self.state.utxo_snapshot(), pubkey);
+        MessageHeader::new()
+            .set_type(MessageType::Balance(balance))
+            .send_to(conn)
+    }
+
+    fn allow_balance_query(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut balance_queries = self.balance_queries.lock().unwrap();
+        balance_queries.retain(|_, (window_start, _)|
+            now.duration_since(*window_start) < BALANCE_QUERY_WINDOW);
+
+        let (_, count) = balance_queries
+            .entry(ip.to_canonical())
+            .or_insert((now, 0));
+        *count += 1;
+        *count <= MAX_BALANCE_QUERIES
+    }
+
+    fn request_missing_txs(&self, tx_ids: &[Sha256Hash]) -> Vec<Sha256Hash> {
+        let mut requested_txs = self.requested_txs.lock().unwrap();
+
+        let now = Instant::now();
+        requested_txs.retain(|_, requested_at|
+            now.duration_since(*requested_at) < TX_REQUEST_TIMEOUT);
+
+        let mut missing = Vec::new();
+        for tx_id in tx_ids {
+            // Known to the mempool or confirmed, through the txid index.
+            if requested_txs.contains_key(tx_id)
+                    || self.state.confirmations(tx_id).is_some() {
+                continue;
             }
+
+            requested_txs.insert(*tx_id, now);
+            missing.push(*tx_id);
         }
+
+        missing
     }
 
-    fn add_peer(&self, conn: TcpStream) {
-        println!("[ADDED PEER][{}:{}]",
-            conn.peer_addr().unwrap().ip(),
-            conn.peer_addr().unwrap().port());
-        self.peers.lock().unwrap().push(conn.try_clone().unwrap());
+    fn receive_transaction(&self, tx: Transaction) {
+        let tx_id = tx.calculate_id();
+        if self.requested_txs.lock().unwrap().remove(&tx_id).is_none() {
+            debug!("[UNSOLICITED TX]");
+            return;
+        }
+
+        if let Err(err) = self.state.submit_transaction(tx) {
+            debug!("[REJECTED TX][{:?}]", err);
+        }
+    }
 
-        thread::spawn(|| listen_to_messages(conn));
+    fn relay_transactions(&self, relayed_txs: Receiver<Sha256Hash>) {
+        while !self.shutdown_flag.load(Ordering::SeqCst) {
+            match relayed_txs.recv_timeout(Duration::from_millis(100)) {
+                Ok(tx_id) => self.broadcast_tx_inv(&[tx_id]),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+        }
     }
 
     fn list_peers(&self, conn: &mut TcpStream) -> Result<()> {
-        println!("[LIST PEERS][{}:{}]",
-            conn.peer_addr().unwrap().ip(),
-            conn.peer_addr().unwrap().port());
+        debug!("[LIST PEERS][{}]", conn.peer_addr()?);
 
         let peers = self.peers.lock().unwrap();
         conn.write_all(&[peers.len() as u8])?;
 
-        for peer in &*peers {
-            let address = peer.peer_addr().unwrap().ip();
+        for (address, port) in peers.keys() {
 
             let mut ip_ver = [4u8];
             if address.is_ipv6() {
                 ip_ver[0] = 6u8;
             }
             conn.write(&ip_ver)?;
-            let address = peer.peer_addr().unwrap().ip();
 
             match address {
                 IpAddr::V4(ref ip) => {
@@ -171,36 +1299,94 @@ impl NetworkInterface {
                     conn.write_all(&ip[..])?;
                 }
             };
+            conn.write_all(&port.to_le_bytes())?;
 
         }
 
         Ok(())
     }
 
-    pub fn bootstrap(&self, ip: IpAddr) {
-        println!("[BOOTSTRAP][{}]", ip);
+    pub fn known_peers(&self) -> Vec<(SocketAddr, SystemTime)> {
+        let known_peers = match &self.known_peers {
+            Some(known_peers) => known_peers.lock().unwrap(),
+            None => return Vec::new()
+        };
+
+        let mut peers = known_peers
+            .iter()
+            .map(|(address, last_seen)| (*address, *last_seen))
+            .collect::<Vec<_>>();
+        peers.sort_by(|(address_a, seen_a), (address_b, seen_b)|
+            seen_b.cmp(seen_a).then(address_a.cmp(address_b)));
+        peers
+    }
+
+    // Tries the most recently seen peers first until the outbound slots are
+    // full. Returns how many connections were made.
+    pub fn connect_known_peers(self: &Arc<Self>) -> usize {
+        let mut connected = 0;
+        for (address, _) in self.known_peers() {
+            if !self.has_free_slot(PeerDirection::Outbound) {
+                break;
+            }
+
+            match self.connect_to_peer(address) {
+                Ok(()) => connected += 1,
+                Err(err) => debug!("[KNOWN PEER UNREACHABLE][{}][{}]", address,
+                    err)
+            }
+        }
+
+        info!("[CONNECTED KNOWN PEERS][{}]", connected);
+        connected
+    }
+
+    fn mark_seen(&self, key: PeerKey) {
+        let mut known_peers = match &self.known_peers {
+            Some(known_peers) => known_peers.lock().unwrap(),
+            None => return
+        };
+
+        known_peers.insert(SocketAddr::from(key), SystemTime::now());
+        while known_peers.len() > MAX_KNOWN_PEERS {
+            let oldest = known_peers
+                .iter()
+                .min_by_key(|(_, last_seen)| **last_seen)
+                .map(|(address, _)| *address)
+                .unwrap();
+            known_peers.remove(&oldest);
+        }
+        known_peers.update();
+    }
+
+    pub fn bootstrap(self: &Arc<Self>, address: SocketAddr) {
+        info!("[BOOTSTRAP][{}]", address);
+
+        if !self.has_free_slot(PeerDirection::Outbound) {
+            warn!("[ALREADY BOOTSTRAPPED]");
+            return;
+        }
 
-        if self.peers.lock().unwrap().len() >= 3 {
-            println!("[ERROR][ALREADY BOOTSTRAPPED]");
+        if self.connect_known_peers() > 0 {
             return;
         }
 
-        let mut nodes_queue = VecDeque::<IpAddr>::new();
-        nodes_queue.push_back(ip);
-        let mut nodes_seen = HashSet::<IpAddr>::new();
-        nodes_seen.insert(ip);
-        let mut nodes = HashMap::<IpAddr, u32>::new();
+        let mut nodes_queue = VecDeque::<SocketAddr>::new();
+        nodes_queue.push_back(address);
+        let mut nodes_seen = HashSet::<SocketAddr>::new();
+        nodes_seen.insert(address);
+        let mut nodes = HashMap::<SocketAddr, u32>::new();
 
         'graph_search: loop {
             for _ in 0..10 {
-                let ip = nodes_queue.pop_back();
-                if let None = ip {
+                let address = nodes_queue.pop_back();
+                if let None = address {
                     break;
                 }
-                let ip = ip.unwrap();
+                let address = address.unwrap();
 
-                if let Ok(val) = self.ask_for_peers(ip) {
-                    nodes.insert(ip, val.len() as u32);
+                if let Ok(val) = self.ask_for_peers(address) {
+                    nodes.insert(address, val.len() as u32);
 
                     for node in val {
                         if !nodes_seen.contains(&node) {
@@ -212,8 +1398,8 @@ impl NetworkInterface {
             }
 
             if nodes.len() == 1 {
-                let (ip, _) = nodes.drain().next().unwrap();
-                let _ = self.connect_to_peer(ip);
+                let (address, _) = nodes.drain().next().unwrap();
+                let _ = self.connect_to_peer(address);
                 break 'graph_search;
             }
 
@@ -232,7 +1418,7 @@ impl NetworkInterface {
 
                 let _ = self.connect_to_peer(min_connections);
 
-                if self.peers.lock().unwrap().len() >= 3 {
+                if !self.has_free_slot(PeerDirection::Outbound) {
                     break 'graph_search;
                 }
             }
@@ -240,30 +1426,97 @@ impl NetworkInterface {
     }
 }
 
-fn listen_to_messages(conn: TcpStream) -> Result<()> {
-    let mut conn = conn;
-    loop {
-        let message = MessageHeader::receive_from(&mut conn)?;
+fn peer_key(address: SocketAddr) -> PeerKey {
+    (address.ip().to_canonical(), address.port())
+}
 
-        println!("[{}:{}][MESSAGE]",
-            conn.peer_addr().unwrap().ip(),
-            conn.peer_addr().unwrap().port()
-        );
+fn block_ban_score(err: &BlockValidityError) -> u32 {
+    match err {
+        BlockValidityError::InvalidHash
+            | BlockValidityError::InvalidMerkleRoot
+            | BlockValidityError::MultipleCoinbase
+            | BlockValidityError::MissingCoinbase
+            | BlockValidityError::MisplacedCoinbase
+            | BlockValidityError::InvalidCoinbaseInputs
+            | BlockValidityError::InvalidCoinbaseHeight
+            | BlockValidityError::BlockTooLarge
+            | BlockValidityError::CheckpointMismatch => INVALID_BLOCK_SCORE,
+        _ => 0
     }
 }
 
-pub fn resolve_address(address: &str) -> Result<IpAddr> {
-    let mut address = address.trim().to_owned();
+fn send_headers(conn: &mut TcpStream, state: &GlobalState, from_height: u32)
+        -> Result<()> {
+
+    debug!("[GET HEADERS][{}][{}]",
+        conn.peer_addr()?,
+        from_height
+    );
+
+    let headers = state.headers(from_height, MAX_HEADERS_PER_MESSAGE);
+    MessageHeader::new()
+        .set_type(MessageType::Headers(headers))
+        .send_to(conn)
+}
+
+fn send_block(conn: &mut TcpStream, state: &GlobalState, hash: &Sha256Hash)
+        -> Result<()> {
+
+    debug!("[GET BLOCK][{}][{}]",
+        conn.peer_addr()?,
+        short_hash(hash)
+    );
 
-    let mut iter = address.split(":");
-    let _ = iter.next();
-    if let None = iter.next() {
-        address = format!("{address}:1234");
+    let block = state.block(hash);
+    MessageHeader::new()
+        .set_type(MessageType::BlockResponse(block))
+        .send_to(conn)
+}
+
+fn send_block_txs(conn: &mut TcpStream, state: &GlobalState,
+        block_hash: &Sha256Hash, indexes: &[u32]) -> Result<()> {
+
+    debug!("[GET BLOCK TXS][{}][{}][{}]",
+        conn.peer_addr()?,
+        short_hash(block_hash),
+        indexes.len()
+    );
+
+    let txs = state.block(block_hash)
+        .and_then(|block| indexes
+            .iter()
+            .map(|i| block.transactions().get(*i as usize).cloned())
+            .collect::<Option<Vec<_>>>())
+        .unwrap_or_default();
+    MessageHeader::new()
+        .set_type(MessageType::BlockTxs { block_hash: *block_hash, txs })
+        .send_to(conn)
+}
+
+fn send_transaction(conn: &mut TcpStream, state: &GlobalState,
+        tx_id: &Sha256Hash) -> Result<()> {
+
+    debug!("[GET TX][{}][{}]",
+        conn.peer_addr()?,
+        short_hash(tx_id)
+    );
+
+    let tx = state.mempool.lock().unwrap().get(tx_id).cloned();
+    match tx {
+        Some(tx) => MessageHeader::new()
+            .set_type(MessageType::TxResponse(tx))
+            .send_to(conn),
+        None => Ok(())
     }
+}
 
+pub fn resolve_address(address: &str, default_port: u16)
+        -> Result<SocketAddr> {
+
+    let address = with_default_port(address.trim(), default_port);
     let resolved_addresses = address.to_socket_addrs()?;
 
-    if let Some(addr) = resolved_addresses.map(|a| a.ip()).next() {
+    if let Some(addr) = resolved_addresses.into_iter().next() {
         return Ok(addr);
     }
 
@@ -273,3 +1526,158 @@ pub fn resolve_address(address: &str) -> Result<IpAddr> {
     ))
 }
 
+fn with_default_port(address: &str, default_port: u16) -> String {
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return SocketAddr::new(ip, default_port).to_string();
+    }
+
+    if address.starts_with('[') && address.ends_with(']') {
+        return format!("{address}:{default_port}");
+    }
+
+    let has_port = match address.rsplit_once(':') {
+        Some((host, port)) => (host.starts_with('[') || !host.contains(':'))
+            && port.parse::<u16>().is_ok(),
+        None => false
+    };
+
+    if has_port {
+        return address.to_owned();
+    }
+
+    format!("{address}:{default_port}")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::GenesisConfig;
+    use crate::blockchain::testutil::TempDir;
+
+    fn free_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn start_node(dir: &TempDir) -> Arc<NetworkInterface> {
+        let genesis = GenesisConfig::new().set_difficulty(4);
+        let state = GlobalState::open(dir.path(), genesis, false, false)
+            .unwrap();
+        let (events, _) = mpsc::channel();
+        let interface = Arc::new(NetworkInterface::new(free_port(),
+            Arc::new(state), 8, 8, events));
+        interface.start();
+        interface
+    }
+
+    // The listener binds on its own thread, so the first dial may be early.
+    fn dial(interface: &Arc<NetworkInterface>, address: SocketAddr)
+            -> Result<()> {
+
+        let mut res = interface.connect_to_peer(address);
+        for _ in 0..20 {
+            match &res {
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                    thread::sleep(Duration::from_millis(50));
+                    res = interface.connect_to_peer(address);
+                }
+                _ => break
+            }
+        }
+
+        res
+    }
+
+    fn wait_for_peers(interface: &NetworkInterface, count: usize) -> bool {
+        for _ in 0..20 {
+            if interface.peer_count() == count {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        false
+    }
+
+    #[test]
+    fn loopback_peers_are_keyed_by_listen_port() {
+        let (dir_a, dir_b) = (TempDir::new(), TempDir::new());
+        let (node_a, node_b) = (start_node(&dir_a), start_node(&dir_b));
+        let address_b = SocketAddr::from((Ipv4Addr::LOCALHOST, node_b.port()));
+
+        dial(&node_a, address_b).unwrap();
+        node_a.connect_to_peer(address_b).unwrap();
+        assert_eq!(node_a.peer_count(), 1);
+        assert!(wait_for_peers(&node_b, 1));
+        assert!(node_b.is_peer((Ipv4Addr::LOCALHOST.into(), node_a.port())));
+
+        node_a.shutdown();
+        node_b.shutdown();
+    }
+
+    #[test]
+    fn dialing_own_listener_is_refused() {
+        let dir = TempDir::new();
+        let node = start_node(&dir);
+        let own_address = SocketAddr::from((Ipv4Addr::LOCALHOST, node.port()));
+
+        let err = dial(&node, own_address).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(node.peer_count(), 0);
+
+        node.shutdown();
+    }
+
+    #[test]
+    fn peers_over_ipv6_loopback() {
+        let (dir_a, dir_b) = (TempDir::new(), TempDir::new());
+        let (node_a, node_b) = (start_node(&dir_a), start_node(&dir_b));
+        let address_b = resolve_address(&format!("[::1]:{}", node_b.port()),
+            0).unwrap();
+        assert_eq!(address_b, SocketAddr::from((Ipv6Addr::LOCALHOST,
+            node_b.port())));
+
+        dial(&node_a, address_b).unwrap();
+        assert_eq!(node_a.peer_count(), 1);
+        assert!(wait_for_peers(&node_b, 1));
+        assert!(node_b.is_peer((Ipv6Addr::LOCALHOST.into(), node_a.port())));
+
+        node_a.shutdown();
+        node_b.shutdown();
+    }
+
+    #[test]
+    fn default_port_is_added_only_when_missing() {
+        assert_eq!(with_default_port("[::1]:1234", 4000), "[::1]:1234");
+        assert_eq!(with_default_port("[::1]", 4000), "[::1]:4000");
+        assert_eq!(with_default_port("2001:db8::1", 4000),
+            "[2001:db8::1]:4000");
+        assert_eq!(with_default_port("10.0.0.1:1234", 4000), "10.0.0.1:1234");
+        assert_eq!(with_default_port("10.0.0.1", 4000), "10.0.0.1:4000");
+        assert_eq!(with_default_port("localhost:1234", 4000),
+            "localhost:1234");
+        assert_eq!(with_default_port("localhost", 4000), "localhost:4000");
+    }
+
+    #[test]
+    fn resolves_every_address_form() {
+        let v6 = |port| SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+        let v4 = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+        assert_eq!(resolve_address("[::1]:1234", 4000).unwrap(), v6(1234));
+        assert_eq!(resolve_address("::1", 4000).unwrap(), v6(4000));
+        assert_eq!(resolve_address(" 127.0.0.1:1234 ", 4000).unwrap(),
+            v4(1234));
+        assert_eq!(resolve_address("127.0.0.1", 4000).unwrap(), v4(4000));
+
+        let host = resolve_address("localhost", 4000).unwrap();
+        assert!(host.ip().is_loopback());
+        assert_eq!(host.port(), 4000);
+        assert_eq!(resolve_address("localhost:1234", 4000).unwrap().port(),
+            1234);
+    }
+}
@@ -1,18 +1,61 @@
 use std::io::{Read, Error, ErrorKind, Result, Write};
 use std::net::TcpStream;
 
+use k256::ecdsa::VerifyingKey;
 use serde::{Serialize, Deserialize};
 
+use crate::blockchain::block::{Block, BlockHeader, MAX_BLOCK_SIZE};
+use crate::blockchain::bloom::BloomFilter;
+use crate::blockchain::hex_serde;
+use crate::blockchain::transaction::{
+    Sha256Hash,
+    Transaction,
+    DEFAULT_CHAIN_ID
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+
+pub const PROTOCOL_VERSION: u16 = 4;
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+pub const MAX_HEADERS_PER_MESSAGE: u32 = 2000;
+const MAX_MESSAGE_SIZE: u32 = 2 * MAX_BLOCK_SIZE as u32;
+const MAX_MAGIC_SCAN: usize = MAX_MESSAGE_SIZE as usize;
+const MAGIC: &[u8] = b"rusty";
+
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum MessageType {
-    StartPeering,
+    StartPeering {
+        protocol_version: u16,
+        genesis_hash: Sha256Hash,
+        chain_id: u32,
+        tip: Sha256Hash,
+        utxo_commitment: Sha256Hash,
+        listen_port: u16,
+        nonce: u64
+    },
     ListPeers,
     Ack,
-    Nack
+    Nack,
+    Reject { reason: String },
+    NewBlock(Block),
+    GetHeaders { from_height: u32 },
+    Headers(Vec<BlockHeader>),
+    GetBlock(Sha256Hash),
+    BlockResponse(Option<Block>),
+    TxInv(Vec<Sha256Hash>),
+    GetTx(Sha256Hash),
+    TxResponse(Transaction),
+    Ping(u64),
+    Pong(u64),
+    GetBalance(#[serde(with = "hex_serde::verifying_key")] VerifyingKey),
+    Balance(u32),
+    CompactBlock { header: BlockHeader, short_tx_ids: Vec<u64> },
+    GetBlockTxs { block_hash: Sha256Hash, indexes: Vec<u32> },
+    BlockTxs { block_hash: Sha256Hash, txs: Vec<Transaction> },
+    SetFilter(BloomFilter)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct MessageHeader {
     pub message_type: MessageType
 }
@@ -20,7 +63,15 @@ pub struct MessageHeader {
 impl MessageHeader {
     pub fn new() -> Self {
         MessageHeader {
-            message_type: MessageType::StartPeering
+            message_type: MessageType::StartPeering {
+                protocol_version: PROTOCOL_VERSION,
+                genesis_hash: [0u8; 32],
+                chain_id: DEFAULT_CHAIN_ID,
+                tip: [0u8; 32],
+                utxo_commitment: [0u8; 32],
+                listen_port: 0,
+                nonce: 0
+            }
         }
     }
 
@@ -38,15 +89,44 @@ impl MessageHeader {
     }
 
     pub fn send_to(&self, conn: &mut TcpStream) -> Result<()> {
-        conn.write_all(b"rusty")?;
         let message = bincode::serialize(self).unwrap();
+        let len = message.len() as u32;
+        let checksum = crc32fast::hash(&message);
+
+        conn.write_all(MAGIC)?;
+        conn.write_all(&len.to_le_bytes())?;
+        conn.write_all(&checksum.to_le_bytes())?;
         conn.write_all(&message)?;
         Ok(())
     }
 
     pub fn receive_from(conn: &mut TcpStream) -> Result<MessageHeader> {
         wait_for_magic(conn)?;
-        bincode::deserialize_from(conn).map_err(|_| Error::new(
+
+        let mut len = [0u8; 4];
+        conn.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_MESSAGE_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Message from peer is too large"
+            ));
+        }
+
+        let mut checksum = [0u8; 4];
+        conn.read_exact(&mut checksum)?;
+        let checksum = u32::from_le_bytes(checksum);
+
+        let mut message = vec![0u8; len as usize];
+        conn.read_exact(&mut message)?;
+        if crc32fast::hash(&message) != checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Message checksum mismatch"
+            ));
+        }
+
+        bincode::deserialize(&message).map_err(|_| Error::new(
             ErrorKind::InvalidData,
             "Invalid message from peer"
         ))
@@ -54,33 +134,37 @@ impl MessageHeader {
 }
 
 fn wait_for_magic(conn: &mut TcpStream) -> Result<()> {
-    let magic = b"rusty";
-    let mut rest: &[u8] = magic;
+    let mut matched = 0;
 
-    loop {
+    for _ in 0..MAX_MAGIC_SCAN {
         let mut buff = [0u8];
-        let res = conn.read(&mut buff);
+        let res = match conn.read(&mut buff) {
+            Ok(val) => val,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        };
 
-        if let Err(_) = res {
-            continue;
-        }
-
-        if res.unwrap() == 0 {
+        if res == 0 {
             return Err(Error::new(
                 ErrorKind::Interrupted,
                 "The connection was interrupted"
             ));
         }
 
-        if buff[0] != rest[0] {
-            rest = magic;
-            continue;
-        }
+        let mut window = MAGIC[..matched].to_vec();
+        window.push(buff[0]);
+        matched = (0..=window.len())
+            .rev()
+            .find(|len| window.ends_with(&MAGIC[..*len]))
+            .unwrap_or(0);
 
-        rest = &rest[1..];
-        if rest.len() == 0 {
+        if matched == MAGIC.len() {
             return Ok(());
         }
     }
-}
 
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "Peer did not send a message magic"
+    ))
+}